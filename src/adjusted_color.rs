@@ -1,15 +1,15 @@
 pub mod adjusted_color {
+    use crate::clock::clock::Clock;
     use anyhow::Result;
-    use chrono::Local;
-    use palette::{Darken, IntoColor, Srgb};
+    use palette::{Darken, IntoColor, LinSrgb, Srgb};
     use raqote::{SolidSource, Source};
     use std::str::FromStr;
     use suncalc::Timestamp;
 
-    pub fn get_sun_darkening() -> f64 {
+    pub fn get_sun_darkening(clock: &dyn Clock) -> f64 {
         let lat = 40.692778;
         let lon = -73.990278;
-        let sun_pos = suncalc::get_position(Timestamp(Local::now().timestamp()), lat, lon);
+        let sun_pos = suncalc::get_position(Timestamp(clock.now().timestamp()), lat, lon);
 
         if sun_pos.altitude < 0.0 {
             // If the sun is down, dark this a lot
@@ -20,10 +20,14 @@ pub mod adjusted_color {
         }
     }
 
-    pub fn adjusted_color_with_tint(hex: &str, tint: f64) -> Result<raqote::Source<'static>> {
+    pub fn adjusted_color_with_tint(
+        hex: &str,
+        tint: f64,
+        clock: &dyn Clock,
+    ) -> Result<raqote::Source<'static>> {
         let mut color = Srgb::from_str(hex)?.into_linear();
 
-        color = color.darken(get_sun_darkening() + tint);
+        color = color.darken(get_sun_darkening(clock) + tint);
 
         let Srgb {
             standard: _,
@@ -35,8 +39,15 @@ pub mod adjusted_color {
         color_to_source(red, green, blue)
     }
 
-    pub fn adjusted_color(hex: &str) -> Result<raqote::Source<'static>> {
-        adjusted_color_with_tint(hex, 0.0)
+    pub fn adjusted_color(hex: &str, clock: &dyn Clock) -> Result<raqote::Source<'static>> {
+        adjusted_color_with_tint(hex, 0.0, clock)
+    }
+
+    /// Black or white, whichever reads better on top of `hex`.
+    pub fn contrasting_color(hex: &str) -> Result<&'static str> {
+        let color: LinSrgb<f64> = Srgb::from_str(hex)?.into_format::<f64>().into_linear();
+        let luminance = 0.2126 * color.red + 0.7152 * color.green + 0.0722 * color.blue;
+        Ok(if luminance > 0.179 { "#000" } else { "#fff" })
     }
 
     pub fn color_to_source(red: f64, green: f64, blue: f64) -> Result<raqote::Source<'static>> {
@@ -0,0 +1,388 @@
+pub mod arrivals_cache {
+    use crate::clock::clock::Clock;
+    use crate::next_buses::{env_number, BusStop, ExpectedBusArrival, NextBusesConfig};
+    use crate::transit_source::transit_source::{is_transient, TransitSource};
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use chrono::{DateTime, Duration, FixedOffset};
+    use serde::{Deserialize, Serialize};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    // How long a response is reused for before asking the API again.
+    const DEFAULT_TTL_SECONDS: i64 = 60;
+
+    // How old a response can be and still stand in when the API is down.
+    const DEFAULT_MAX_STALE_SECONDS: i64 = 60 * 60;
+
+    /// The last good visits to a stop, as written to disk. They're kept
+    /// unfiltered so that they can be selected from again as time passes.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct CacheEntry {
+        fetched_at: DateTime<FixedOffset>,
+        visits: Vec<ExpectedBusArrival>,
+    }
+
+    /// Keeps the last good visits to each stop in the cache directory.
+    /// Fresh entries are used instead of calling the live source, and
+    /// stale ones stand in for it when it can't be reached, for up to
+    /// `max_stale`.
+    pub struct CachedSource {
+        pub live: Arc<dyn TransitSource>,
+        pub dir: PathBuf,
+        pub ttl: Duration,
+        pub max_stale: Duration,
+    }
+
+    impl CachedSource {
+        pub fn new(live: Arc<dyn TransitSource>, dir: PathBuf) -> CachedSource {
+            CachedSource {
+                live,
+                dir,
+                ttl: Duration::seconds(DEFAULT_TTL_SECONDS),
+                max_stale: Duration::seconds(DEFAULT_MAX_STALE_SECONDS),
+            }
+        }
+
+        pub fn set_ttl(mut self, ttl: Duration) -> Self {
+            self.ttl = ttl;
+            self
+        }
+
+        pub fn set_max_stale(mut self, max_stale: Duration) -> Self {
+            self.max_stale = max_stale;
+            self
+        }
+
+        /// Caches in the user's cache directory, with the TTL and maximum
+        /// stale age in seconds from `CACHE_TTL` and `CACHE_MAX_STALE`.
+        /// There's nothing to cache in without a
+        /// cache directory, so that leaves `live` as it is. The cache only
+        /// helps where that directory outlives a run, so the workflow
+        /// restores it with `actions/cache`.
+        pub fn wrap_from_env(live: Arc<dyn TransitSource>) -> Result<Arc<dyn TransitSource>> {
+            let Some(dir) = dirs::cache_dir() else {
+                return Ok(live);
+            };
+            let mut source = CachedSource::new(live, dir.join("tidbyt-rs"));
+            if let Some(ttl) = env_number("CACHE_TTL")? {
+                source = source.set_ttl(Duration::seconds(ttl));
+            }
+            if let Some(max_stale) = env_number("CACHE_MAX_STALE")? {
+                source = source.set_max_stale(Duration::seconds(max_stale));
+            }
+            Ok(Arc::new(source))
+        }
+
+        // One file per source and stop, since a stop's code can mean
+        // different things to different sources. The limits passed on to
+        // the API change what it answers with, so they're kept apart too.
+        fn path(&self, config: &NextBusesConfig, stop: &BusStop) -> PathBuf {
+            let file_name = |name: &str| -> String {
+                name.chars()
+                    .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+                    .collect()
+            };
+            let mut parts = vec![file_name(self.live.name()), file_name(&stop.naptan_code)];
+            if let Some(minutes) = config.preview_interval {
+                parts.push(format!("p{}", minutes));
+            }
+            if let Some(visits) = config.maximum_stop_visits {
+                parts.push(format!("v{}", visits));
+            }
+            if let Some(line) = config.filter.single_line() {
+                parts.push(format!("l{}", file_name(&line)));
+            }
+            self.dir.join(format!("arrivals-{}.json", parts.join("-")))
+        }
+
+        fn read(&self, config: &NextBusesConfig, stop: &BusStop) -> Option<CacheEntry> {
+            let json = std::fs::read_to_string(self.path(config, stop)).ok()?;
+            serde_json::from_str(&json).ok()
+        }
+
+        fn write(
+            &self,
+            config: &NextBusesConfig,
+            stop: &BusStop,
+            entry: &CacheEntry,
+        ) -> Result<()> {
+            std::fs::create_dir_all(&self.dir)?;
+            std::fs::write(self.path(config, stop), serde_json::to_string(entry)?)?;
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl TransitSource for CachedSource {
+        fn name(&self) -> &str {
+            self.live.name()
+        }
+
+        // Times are kept as clock times, so selecting from cached visits
+        // again brings the countdowns up to date and drops buses that have gone.
+        async fn stop_visits(
+            &self,
+            config: &NextBusesConfig,
+            stop: &BusStop,
+            clock: &dyn Clock,
+        ) -> Result<Vec<ExpectedBusArrival>> {
+            let cached = self.read(config, stop);
+
+            if let Some(entry) = &cached {
+                let age = clock.now() - entry.fetched_at;
+                if age >= Duration::zero() && age < self.ttl {
+                    return Ok(entry.visits.clone());
+                }
+            }
+
+            match self.live.stop_visits(config, stop, clock).await {
+                Ok(visits) => {
+                    let entry = CacheEntry {
+                        fetched_at: clock.now(),
+                        visits,
+                    };
+                    if let Err(err) = self.write(config, stop, &entry) {
+                        println!("Could not cache arrivals for {}: {}", stop.naptan_code, err);
+                    }
+                    Ok(entry.visits)
+                }
+                Err(err) if !is_transient(&err) => Err(err),
+                Err(err) => match cached {
+                    Some(entry) if clock.now() - entry.fetched_at < self.max_stale => {
+                        println!(
+                            "Using cached arrivals for {} from {}: {}",
+                            stop.naptan_code, entry.fetched_at, err
+                        );
+                        Ok(entry
+                            .visits
+                            .into_iter()
+                            .map(|arrival| ExpectedBusArrival {
+                                stale: true,
+                                ..arrival
+                            })
+                            .collect())
+                    }
+                    Some(_) => {
+                        // Too old to be any use, so don't offer it again.
+                        let _ = std::fs::remove_file(self.path(config, stop));
+                        Err(err)
+                    }
+                    None => Err(err),
+                },
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::clock::clock::FixedClock;
+        use crate::next_buses::{ArrivalFilter, SiriError, SiriErrorKind};
+        use crate::transit_source::transit_source::timed_out;
+        use rstest::*;
+        use std::sync::Mutex;
+        use tempfile::TempDir;
+
+        // Gives out each response once, then times out like a broken API.
+        struct Scripted {
+            responses: Mutex<Vec<Result<Vec<ExpectedBusArrival>>>>,
+        }
+
+        #[async_trait]
+        impl TransitSource for Scripted {
+            fn name(&self) -> &str {
+                "scripted"
+            }
+
+            async fn stop_visits(
+                &self,
+                _config: &NextBusesConfig,
+                _stop: &BusStop,
+                _clock: &dyn Clock,
+            ) -> Result<Vec<ExpectedBusArrival>> {
+                let response = self.responses.lock().unwrap().pop();
+                match response {
+                    Some(response) => response,
+                    None => Err(timed_out().await),
+                }
+            }
+        }
+
+        fn at(time: &str) -> FixedClock {
+            FixedClock(DateTime::parse_from_rfc3339(time).unwrap())
+        }
+
+        fn arrival(line: &str, time: &str) -> ExpectedBusArrival {
+            ExpectedBusArrival {
+                stop: Some("45242629".to_string()),
+                ..ExpectedBusArrival::example(line, time)
+            }
+        }
+
+        fn config() -> NextBusesConfig {
+            NextBusesConfig::new(vec![BusStop {
+                naptan_code: "45242629".to_string(),
+                minutes_away: 0,
+            }])
+        }
+
+        // `responses` are given out last first. The cache lasts as long
+        // as the returned directory.
+        fn cached_source(
+            responses: Vec<Result<Vec<ExpectedBusArrival>>>,
+        ) -> (TempDir, CachedSource) {
+            let dir = TempDir::new().unwrap();
+            let live = Scripted {
+                responses: Mutex::new(responses),
+            };
+            let source = CachedSource::new(Arc::new(live), dir.path().to_path_buf());
+            (dir, source)
+        }
+
+        #[rstest]
+        #[tokio::test]
+        pub async fn reuses_fresh_responses() {
+            let (_dir, source) =
+                cached_source(vec![Ok(vec![arrival("61", "2024-03-09T15:35:00Z")])]);
+            let config = config();
+            let stop = &config.stops[0];
+            let first = source
+                .stop_arrivals(&config, stop, &at("2024-03-09T15:21:00Z"))
+                .await
+                .unwrap();
+            let second = source
+                .stop_arrivals(&config, stop, &at("2024-03-09T15:21:30Z"))
+                .await
+                .unwrap();
+            // The API has nothing more to give, so this came from the cache.
+            assert_eq!(first, second);
+            assert!(!second[0].stale);
+        }
+
+        #[rstest]
+        #[tokio::test]
+        pub async fn falls_back_to_stale_arrivals() {
+            let (_dir, source) = cached_source(vec![Ok(vec![
+                arrival("60A", "2024-03-09T15:26:00Z"),
+                arrival("61", "2024-03-09T15:35:00Z"),
+            ])]);
+            let config = config();
+            let stop = &config.stops[0];
+            source
+                .stop_arrivals(&config, stop, &at("2024-03-09T15:21:00Z"))
+                .await
+                .unwrap();
+            // Long after the TTL, with the API now failing.
+            let arrivals = source
+                .stop_arrivals(&config, stop, &at("2024-03-09T15:30:00Z"))
+                .await
+                .unwrap();
+            let lines: Vec<_> = arrivals
+                .iter()
+                .map(|arrival| (arrival.line.as_str(), arrival.stale))
+                .collect();
+            assert_eq!(lines, vec![("61", true)]);
+        }
+
+        #[rstest]
+        #[tokio::test]
+        pub async fn passes_on_errors_from_the_api() {
+            let (_dir, source) = cached_source(vec![
+                Err(SiriError::new(SiriErrorKind::UnknownStop).into()),
+                Ok(vec![arrival("61", "2024-03-09T15:35:00Z")]),
+            ]);
+            let config = config();
+            let stop = &config.stops[0];
+            source
+                .stop_arrivals(&config, stop, &at("2024-03-09T15:21:00Z"))
+                .await
+                .unwrap();
+            let err = source
+                .stop_arrivals(&config, stop, &at("2024-03-09T15:30:00Z"))
+                .await
+                .unwrap_err();
+            assert!(err.is::<SiriError>());
+        }
+
+        #[rstest]
+        #[tokio::test]
+        pub async fn drops_arrivals_too_old_to_show() {
+            let (_dir, source) =
+                cached_source(vec![Ok(vec![arrival("61", "2024-03-09T19:00:00Z")])]);
+            let source = source.set_max_stale(Duration::minutes(30));
+            let config = config();
+            let stop = &config.stops[0];
+            source
+                .stop_arrivals(&config, stop, &at("2024-03-09T15:21:00Z"))
+                .await
+                .unwrap();
+            assert!(source
+                .stop_arrivals(&config, stop, &at("2024-03-09T15:51:00Z"))
+                .await
+                .is_err());
+            assert!(!source.path(&config, stop).exists());
+        }
+
+        #[rstest]
+        #[tokio::test]
+        pub async fn caches_visits_before_selecting() {
+            let (_dir, source) = cached_source(vec![Ok(vec![
+                arrival("60A", "2024-03-09T15:26:00Z"),
+                arrival("61", "2024-03-09T15:35:00Z"),
+            ])]);
+            let one = NextBusesConfig {
+                max_results: 1,
+                ..config()
+            };
+            let first = source
+                .stop_arrivals(&one, &one.stops[0], &at("2024-03-09T15:21:00Z"))
+                .await
+                .unwrap();
+            let config = config();
+            let second = source
+                .stop_arrivals(&config, &config.stops[0], &at("2024-03-09T15:21:30Z"))
+                .await
+                .unwrap();
+            assert_eq!(first.len(), 1);
+            assert_eq!(second.len(), 2);
+        }
+
+        #[rstest]
+        pub fn keeps_sources_apart() {
+            let (_dir, source) = cached_source(vec![]);
+            let config = config();
+            assert!(source
+                .path(&config, &config.stops[0])
+                .ends_with("arrivals-scripted-45242629.json"));
+        }
+
+        #[rstest]
+        pub fn keeps_api_limits_apart() {
+            let (_dir, source) = cached_source(vec![]);
+            let config = NextBusesConfig {
+                preview_interval: Some(90),
+                maximum_stop_visits: Some(10),
+                filter: ArrivalFilter {
+                    include_lines: vec!["60A".to_string()],
+                    ..Default::default()
+                },
+                ..config()
+            };
+            assert!(source
+                .path(&config, &config.stops[0])
+                .ends_with("arrivals-scripted-45242629-p90-v10-l60A.json"));
+        }
+
+        #[rstest]
+        #[tokio::test]
+        pub async fn fails_without_a_cached_response() {
+            let (_dir, source) = cached_source(vec![]);
+            let config = config();
+            assert!(source
+                .stop_arrivals(&config, &config.stops[0], &at("2024-03-09T15:21:00Z"))
+                .await
+                .is_err());
+        }
+    }
+}
@@ -0,0 +1,28 @@
+pub mod clock {
+    use chrono::{DateTime, FixedOffset, Local};
+
+    /// Where the current time comes from, so that the display can be
+    /// rendered for any moment rather than only for right now.
+    pub trait Clock: Send + Sync {
+        fn now(&self) -> DateTime<FixedOffset>;
+    }
+
+    #[derive(Copy, Clone, Debug, Default)]
+    pub struct SystemClock;
+
+    impl Clock for SystemClock {
+        fn now(&self) -> DateTime<FixedOffset> {
+            Local::now().into()
+        }
+    }
+
+    /// A clock that is always at the given moment.
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub struct FixedClock(pub DateTime<FixedOffset>);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> DateTime<FixedOffset> {
+            self.0
+        }
+    }
+}
@@ -0,0 +1,191 @@
+pub mod departure_time {
+    use crate::clock::clock::Clock;
+    use crate::next_buses::{ExpectedBusArrival, Punctuality};
+    use anyhow::{anyhow, Error, Result};
+    use chrono::Locale;
+    use chrono_tz::Tz;
+    use std::str::FromStr;
+
+    /// How departure times are written on the board.
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+    pub enum TimeDisplay {
+        /// Clock time, e.g. "15:26".
+        #[default]
+        Absolute,
+        /// Minutes until departure, e.g. "5 min".
+        Countdown,
+        /// Minutes until departure below the threshold, clock time beyond it.
+        Hybrid(u32),
+    }
+
+    impl TimeDisplay {
+        pub fn format(
+            &self,
+            arrival: &ExpectedBusArrival,
+            timezone: &Tz,
+            clock: &dyn Clock,
+        ) -> String {
+            // A bus that has already gone reports an error rather than
+            // negative minutes, and is as good as due.
+            let minutes = arrival.minutes_from_now(clock).unwrap_or(0);
+            let countdown = || match minutes {
+                0 => "Due".to_string(),
+                m => format!("{} min", m),
+            };
+            let time = match self {
+                TimeDisplay::Absolute => clock_time(arrival, timezone),
+                TimeDisplay::Countdown => countdown(),
+                TimeDisplay::Hybrid(threshold) if minutes < *threshold => countdown(),
+                TimeDisplay::Hybrid(_) => clock_time(arrival, timezone),
+            };
+            // Timetabled times aren't tracking a real bus, so mark them
+            // as approximate.
+            if arrival.real_time {
+                time
+            } else {
+                format!("~{}", time)
+            }
+        }
+    }
+
+    /// Minutes until each departure, e.g. "3 12 27", with timetabled
+    /// ones marked as approximate.
+    pub fn group_times(arrivals: &[ExpectedBusArrival], clock: &dyn Clock) -> String {
+        arrivals
+            .iter()
+            .map(|arrival| {
+                let minutes = arrival.minutes_from_now(clock).unwrap_or(0);
+                if arrival.real_time {
+                    minutes.to_string()
+                } else {
+                    format!("~{}", minutes)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// The colour to draw a departure time in, given how late the bus is.
+    pub fn punctuality_color(arrival: &ExpectedBusArrival) -> &'static str {
+        match arrival.punctuality() {
+            Punctuality::OnTime => "#fff",
+            Punctuality::Late => "#fa0",
+            Punctuality::VeryLate => "#f00",
+        }
+    }
+
+    fn clock_time(arrival: &ExpectedBusArrival, timezone: &Tz) -> String {
+        arrival
+            .expected_time
+            .with_timezone(timezone)
+            .format_localized("%H:%M", Locale::en_GB)
+            .to_string()
+    }
+
+    /// Parses "absolute", "countdown" or "hybrid:<minutes>".
+    impl FromStr for TimeDisplay {
+        type Err = Error;
+
+        fn from_str(s: &str) -> Result<Self> {
+            match s.split_once(':') {
+                None if s == "absolute" => Ok(TimeDisplay::Absolute),
+                None if s == "countdown" => Ok(TimeDisplay::Countdown),
+                Some(("hybrid", threshold)) => Ok(TimeDisplay::Hybrid(threshold.parse()?)),
+                _ => Err(anyhow!("Unknown time display {}", s)),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::clock::clock::FixedClock;
+        use chrono::{DateTime, Duration};
+        use rstest::*;
+
+        fn clock() -> FixedClock {
+            FixedClock(DateTime::parse_from_rfc3339("2024-03-09T15:21:17Z").unwrap())
+        }
+
+        fn arrival_in(seconds: i64) -> ExpectedBusArrival {
+            ExpectedBusArrival {
+                expected_time: clock().now() + Duration::seconds(seconds),
+                ..ExpectedBusArrival::example("60A", "2024-03-09T15:21:17Z")
+            }
+        }
+
+        #[rstest]
+        #[case(TimeDisplay::Countdown, 30, "Due")]
+        #[case(TimeDisplay::Countdown, -90, "Due")]
+        #[case(TimeDisplay::Countdown, 5 * 60 + 30, "5 min")]
+        #[case(TimeDisplay::Hybrid(10), 5 * 60 + 30, "5 min")]
+        #[case(TimeDisplay::Hybrid(10), 30, "Due")]
+        pub fn formats_countdowns(
+            #[case] display: TimeDisplay,
+            #[case] seconds: i64,
+            #[case] expected: &str,
+        ) {
+            let arrival = arrival_in(seconds);
+            assert_eq!(
+                display.format(&arrival, &chrono_tz::UTC, &clock()),
+                expected
+            );
+        }
+
+        #[rstest]
+        #[case(TimeDisplay::Absolute, 5 * 60 + 30)]
+        #[case(TimeDisplay::Hybrid(10), 20 * 60 + 30)]
+        pub fn formats_clock_times(#[case] display: TimeDisplay, #[case] seconds: i64) {
+            let arrival = arrival_in(seconds);
+            let expected = arrival
+                .expected_time
+                .with_timezone(&chrono_tz::UTC)
+                .format("%H:%M")
+                .to_string();
+            assert_eq!(
+                display.format(&arrival, &chrono_tz::UTC, &clock()),
+                expected
+            );
+        }
+
+        #[rstest]
+        pub fn marks_scheduled_times_as_approximate() {
+            let arrival = ExpectedBusArrival {
+                real_time: false,
+                ..arrival_in(5 * 60 + 30)
+            };
+            assert_eq!(
+                TimeDisplay::Countdown.format(&arrival, &chrono_tz::UTC, &clock()),
+                "~5 min"
+            );
+        }
+
+        #[rstest]
+        pub fn lists_minutes_for_a_group() {
+            let arrivals = vec![
+                arrival_in(3 * 60 + 10),
+                arrival_in(12 * 60),
+                ExpectedBusArrival {
+                    real_time: false,
+                    ..arrival_in(27 * 60 + 50)
+                },
+            ];
+            assert_eq!(group_times(&arrivals, &clock()), "3 12 ~27");
+        }
+
+        #[rstest]
+        #[case("absolute", TimeDisplay::Absolute)]
+        #[case("countdown", TimeDisplay::Countdown)]
+        #[case("hybrid:15", TimeDisplay::Hybrid(15))]
+        pub fn parses_time_display(#[case] input: &str, #[case] expected: TimeDisplay) {
+            assert_eq!(input.parse::<TimeDisplay>().unwrap(), expected);
+        }
+
+        #[rstest]
+        #[case("relative")]
+        #[case("hybrid:soon")]
+        pub fn rejects_unknown_time_display(#[case] input: &str) {
+            assert!(input.parse::<TimeDisplay>().is_err());
+        }
+    }
+}
@@ -14,11 +14,10 @@ pub mod draw_buffer {
             let mut g = (pixel >> 8) & 0xffu32;
             let mut b = *pixel & 0xffu32;
 
-            if a > 0u32 {
-                r = r * 255u32 / a;
-                g = g * 255u32 / a;
-                b = b * 255u32 / a;
-            }
+            // Transparent pixels have nothing to un-premultiply.
+            r = (r * 255u32).checked_div(a).unwrap_or(r);
+            g = (g * 255u32).checked_div(a).unwrap_or(g);
+            b = (b * 255u32).checked_div(a).unwrap_or(b);
 
             output.push(r as u8);
             output.push(g as u8);
@@ -0,0 +1,217 @@
+pub mod font {
+    use anyhow::{anyhow, Error, Result};
+    use once_cell::sync::{Lazy, OnceCell};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    // Parsed glyphs for each font, indexed by the `Font` discriminant.
+    // Parsing a BDF file is slow, so each font is only read the first
+    // time something asks for it.
+    static GLYPH_CACHE: Lazy<[OnceCell<FontGlyphs>; Font::ALL.len()]> = Lazy::new(Default::default);
+
+    /// Every BDF font bundled in the `fonts` directory.
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+    pub enum Font {
+        #[default]
+        Tb8,
+        TomThumb,
+        TomThumb2,
+        Fixed5x8,
+        Fixed6x13,
+        Fixed10x20,
+        Dina,
+        CgPixel3x5,
+        CgPixel4x5,
+    }
+
+    impl Font {
+        pub const ALL: [Font; 9] = [
+            Font::Tb8,
+            Font::TomThumb,
+            Font::TomThumb2,
+            Font::Fixed5x8,
+            Font::Fixed6x13,
+            Font::Fixed10x20,
+            Font::Dina,
+            Font::CgPixel3x5,
+            Font::CgPixel4x5,
+        ];
+
+        pub fn name(&self) -> &'static str {
+            match self {
+                Font::Tb8 => "tb-8",
+                Font::TomThumb => "tom-thumb",
+                Font::TomThumb2 => "tom-thumb-2",
+                Font::Fixed5x8 => "5x8",
+                Font::Fixed6x13 => "6x13",
+                Font::Fixed10x20 => "10x20",
+                Font::Dina => "dina",
+                Font::CgPixel3x5 => "cg-pixel-3x5",
+                Font::CgPixel4x5 => "cg-pixel-4x5",
+            }
+        }
+
+        pub fn bdf_bytes(&self) -> &'static [u8] {
+            match self {
+                Font::Tb8 => include_bytes!("../fonts/tb-8.bdf"),
+                Font::TomThumb => include_bytes!("../fonts/tom-thumb.bdf"),
+                Font::TomThumb2 => include_bytes!("../fonts/tom-thumb-2.bdf"),
+                Font::Fixed5x8 => include_bytes!("../fonts/5x8.bdf"),
+                Font::Fixed6x13 => include_bytes!("../fonts/6x13.bdf"),
+                Font::Fixed10x20 => include_bytes!("../fonts/10x20.bdf"),
+                Font::Dina => include_bytes!("../fonts/Dina_r400-6.bdf"),
+                Font::CgPixel3x5 => include_bytes!("../fonts/CG-pixel-3x5-mono.bdf"),
+                Font::CgPixel4x5 => include_bytes!("../fonts/CG-pixel-4x5-mono.bdf"),
+            }
+        }
+
+        /// tb-8 has always used a narrower space than its own glyph,
+        /// so keep that rather than changing every existing layout.
+        pub fn space_width(&self) -> Option<f32> {
+            match self {
+                Font::Tb8 => Some(2.0),
+                _ => None,
+            }
+        }
+
+        pub fn parse(&self) -> Result<bdf::Font> {
+            Ok(bdf::read(self.bdf_bytes())?)
+        }
+
+        /// The cached glyphs for this font, parsing it on first use.
+        pub fn glyphs(&self) -> Result<&'static FontGlyphs> {
+            GLYPH_CACHE[*self as usize]
+                .get_or_try_init(|| Ok(FontGlyphs::new(*self, &self.parse()?)))
+        }
+    }
+
+    /// A single glyph, with its lit pixels already offset so that
+    /// they sit on the font's baseline.
+    #[derive(Clone, Debug)]
+    pub struct Glyph {
+        pub pixels: Vec<(i32, i32)>,
+        pub advance: f32,
+    }
+
+    #[derive(Debug)]
+    pub struct FontGlyphs {
+        glyphs: HashMap<char, Glyph>,
+        pub line_height: f32,
+    }
+
+    impl FontGlyphs {
+        fn new(font: Font, bdf_font: &bdf::Font) -> FontGlyphs {
+            let font_bounds = bdf_font.bounds();
+            let ascent = font_bounds.height as i32 + font_bounds.y;
+            let glyphs = bdf_font
+                .glyphs()
+                .iter()
+                .map(|(c, glyph)| {
+                    // Most of the bundled fonts don't fill their bounding box
+                    // for every character, so offset each glyph by its own bounds.
+                    let bounds = glyph.bounds();
+                    let offset_x = bounds.x;
+                    let offset_y = ascent - (bounds.height as i32 + bounds.y);
+                    let pixels = glyph
+                        .pixels()
+                        .filter(|(_, lit)| *lit)
+                        .map(|((x, y), _)| (x as i32 + offset_x, y as i32 + offset_y))
+                        .collect();
+                    let advance = match (c, font.space_width()) {
+                        (' ', Some(width)) => width,
+                        _ => glyph.device_width().map(|dw| dw.0).unwrap_or(glyph.width()) as f32,
+                    };
+                    (*c, Glyph { pixels, advance })
+                })
+                .collect();
+            FontGlyphs {
+                glyphs,
+                line_height: font_bounds.height as f32,
+            }
+        }
+
+        pub fn get(&self, c: char) -> Option<&Glyph> {
+            self.glyphs.get(&c)
+        }
+
+        pub fn advance(&self, c: char) -> f32 {
+            self.get(c).map(|glyph| glyph.advance).unwrap_or(0.0)
+        }
+
+        pub fn measure(&self, text: &str) -> f32 {
+            text.chars().map(|c| self.advance(c)).sum()
+        }
+
+        /// `text` without anything the font has no glyph for.
+        pub fn drawable(&self, text: &str) -> String {
+            text.chars().filter(|c| self.get(*c).is_some()).collect()
+        }
+    }
+
+    impl FromStr for Font {
+        type Err = Error;
+
+        fn from_str(s: &str) -> Result<Self> {
+            Font::ALL
+                .into_iter()
+                .find(|font| font.name().eq_ignore_ascii_case(s))
+                .ok_or_else(|| anyhow!("Unknown font {}", s))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use rstest::*;
+
+        #[rstest]
+        pub fn font_names_round_trip() {
+            for font in Font::ALL {
+                assert_eq!(font.name().parse::<Font>().unwrap(), font);
+            }
+            assert!("comic-sans".parse::<Font>().is_err());
+        }
+
+        #[rstest]
+        #[case(Font::Tb8, 8.0)]
+        #[case(Font::Fixed10x20, 20.0)]
+        #[case(Font::CgPixel3x5, 5.0)]
+        pub fn fonts_report_their_line_height(#[case] font: Font, #[case] height: f32) {
+            assert_eq!(font.glyphs().unwrap().line_height, height);
+        }
+
+        #[rstest]
+        pub fn glyphs_are_parsed_once() {
+            let first = Font::Tb8.glyphs().unwrap();
+            let second = Font::Tb8.glyphs().unwrap();
+            assert!(std::ptr::eq(first, second));
+        }
+
+        #[rstest]
+        pub fn tb8_keeps_its_old_metrics() {
+            // Before the registry, tb-8 glyphs were drawn straight from
+            // their bitmaps and advanced by their width, so layouts built
+            // on it shouldn't move.
+            let bdf_font = Font::Tb8.parse().unwrap();
+            let glyphs = Font::Tb8.glyphs().unwrap();
+            for (c, glyph) in bdf_font.glyphs().iter().filter(|(c, _)| **c != ' ') {
+                let pixels: Vec<(i32, i32)> = glyph
+                    .pixels()
+                    .filter(|(_, lit)| *lit)
+                    .map(|((x, y), _)| (x as i32, y as i32))
+                    .collect();
+                let parsed = glyphs.get(*c).unwrap();
+                assert_eq!(parsed.pixels, pixels, "{:?}", c);
+                assert_eq!(parsed.advance, glyph.width() as f32, "{:?}", c);
+            }
+        }
+
+        #[rstest]
+        #[case(Font::Tb8, "12:34", 22.0)]
+        #[case(Font::Tb8, "60 A", 17.0)]
+        #[case(Font::CgPixel3x5, "12:34", 20.0)]
+        pub fn measures_text(#[case] font: Font, #[case] text: &str, #[case] width: f32) {
+            assert_eq!(font.glyphs().unwrap().measure(text), width);
+        }
+    }
+}
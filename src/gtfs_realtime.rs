@@ -0,0 +1,398 @@
+pub mod gtfs_realtime {
+    use crate::clock::clock::Clock;
+    use crate::gtfs_static::gtfs_static::route_names;
+    use crate::next_buses::{
+        env_var, BusStop, DepartureStatus, ExpectedBusArrival, NextBusesConfig,
+    };
+    use crate::transit_source::transit_source::TransitSource;
+    use anyhow::{anyhow, Result};
+    use async_trait::async_trait;
+    use chrono::{DateTime, Duration, FixedOffset};
+    use prost::Message;
+    use reqwest::header::USER_AGENT;
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::sync::Arc;
+    use tokio::sync::OnceCell;
+
+    // Only the parts of gtfs-realtime.proto that are read below. Prost
+    // skips any other fields, such as vehicle positions and alerts.
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct FeedMessage {
+        #[prost(message, repeated, tag = "2")]
+        pub entity: Vec<FeedEntity>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct FeedEntity {
+        #[prost(string, required, tag = "1")]
+        pub id: String,
+        #[prost(message, optional, tag = "3")]
+        pub trip_update: Option<TripUpdate>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct TripUpdate {
+        #[prost(message, required, tag = "1")]
+        pub trip: TripDescriptor,
+        #[prost(message, repeated, tag = "2")]
+        pub stop_time_update: Vec<StopTimeUpdate>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct TripDescriptor {
+        #[prost(string, optional, tag = "1")]
+        pub trip_id: Option<String>,
+        #[prost(enumeration = "TripScheduleRelationship", optional, tag = "4")]
+        pub schedule_relationship: Option<i32>,
+        #[prost(string, optional, tag = "5")]
+        pub route_id: Option<String>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    pub enum TripScheduleRelationship {
+        Scheduled = 0,
+        Added = 1,
+        Unscheduled = 2,
+        Canceled = 3,
+        Replacement = 5,
+        Duplicated = 6,
+        Deleted = 7,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct StopTimeUpdate {
+        #[prost(message, optional, tag = "2")]
+        pub arrival: Option<StopTimeEvent>,
+        #[prost(message, optional, tag = "3")]
+        pub departure: Option<StopTimeEvent>,
+        #[prost(string, optional, tag = "4")]
+        pub stop_id: Option<String>,
+        #[prost(enumeration = "ScheduleRelationship", optional, tag = "5")]
+        pub schedule_relationship: Option<i32>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct StopTimeEvent {
+        #[prost(int32, optional, tag = "1")]
+        pub delay: Option<i32>,
+        #[prost(int64, optional, tag = "2")]
+        pub time: Option<i64>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    pub enum ScheduleRelationship {
+        Scheduled = 0,
+        Skipped = 1,
+        NoData = 2,
+    }
+
+    impl StopTimeUpdate {
+        // Prefer the departure time, since that is when the bus leaves.
+        fn event(&self) -> Option<&StopTimeEvent> {
+            self.departure.as_ref().or(self.arrival.as_ref())
+        }
+    }
+
+    /// The departures from `stop_id` in a TripUpdates feed, soonest first.
+    /// The realtime feed only gives each trip's route id, so that's shown
+    /// as the line unless `route_names` has a name for it.
+    pub fn departures(
+        feed: &FeedMessage,
+        stop_id: &str,
+        route_names: &HashMap<String, String>,
+    ) -> Vec<ExpectedBusArrival> {
+        let mut arrivals: Vec<ExpectedBusArrival> = feed
+            .entity
+            .iter()
+            .filter_map(|entity| entity.trip_update.as_ref())
+            // Deleted trips aren't meant to be shown at all.
+            .filter(|trip_update| {
+                trip_update.trip.schedule_relationship() != TripScheduleRelationship::Deleted
+            })
+            .flat_map(|trip_update| {
+                let cancelled =
+                    trip_update.trip.schedule_relationship() == TripScheduleRelationship::Canceled;
+                trip_update
+                    .stop_time_update
+                    .iter()
+                    .filter(|update| update.stop_id.as_deref() == Some(stop_id))
+                    // A cancelled trip's stops may all be marked as skipped.
+                    .filter(move |update| {
+                        cancelled
+                            || update.schedule_relationship() == ScheduleRelationship::Scheduled
+                    })
+                    .filter_map(|update| arrival(trip_update, update.event()?, route_names))
+            })
+            .collect();
+        arrivals.sort_by_key(|arrival| arrival.expected_time);
+        arrivals
+    }
+
+    fn arrival(
+        trip_update: &TripUpdate,
+        event: &StopTimeEvent,
+        route_names: &HashMap<String, String>,
+    ) -> Option<ExpectedBusArrival> {
+        let expected_time: DateTime<FixedOffset> = DateTime::from_timestamp(event.time?, 0)?.into();
+        let aimed_time = event
+            .delay
+            .map(|delay| expected_time - Duration::seconds(delay.into()));
+        let route_id = trip_update.trip.route_id.as_ref()?;
+        let departure_status = (trip_update.trip.schedule_relationship()
+            == TripScheduleRelationship::Canceled)
+            .then_some(DepartureStatus::Cancelled);
+        Some(ExpectedBusArrival {
+            line: route_names.get(route_id).unwrap_or(route_id).clone(),
+            direction: None,
+            stop: None,
+            atco_code: None,
+            walking_minutes: 0,
+            expected_time,
+            aimed_time,
+            real_time: true,
+            departure_status,
+            operator: None,
+            vehicle_mode: None,
+            recorded_at: None,
+            stale: false,
+        })
+    }
+
+    /// A GTFS-Realtime TripUpdates feed, as published by most agencies
+    /// outside the UK.
+    #[derive(Clone, Debug)]
+    pub struct GtfsRealtimeSource {
+        pub url: String,
+        pub route_names: HashMap<String, String>,
+        // Fetched on first use, for every configured stop at once.
+        feed: Arc<OnceCell<FeedMessage>>,
+    }
+
+    impl GtfsRealtimeSource {
+        pub fn new(url: String) -> GtfsRealtimeSource {
+            GtfsRealtimeSource {
+                url,
+                route_names: HashMap::new(),
+                feed: Arc::new(OnceCell::new()),
+            }
+        }
+
+        pub fn set_route_names(mut self, route_names: HashMap<String, String>) -> Self {
+            self.route_names = route_names;
+            self
+        }
+
+        /// Reads the feed's URL from `GTFS_RT_URL`, and names its routes
+        /// from the timetable at `GTFS_STATIC_ZIP` if there is one.
+        pub fn from_env() -> Result<GtfsRealtimeSource> {
+            let source = GtfsRealtimeSource::new(
+                env_var("GTFS_RT_URL")
+                    .ok_or_else(|| anyhow!("GTFS_RT_URL is needed for the gtfs-rt source"))?,
+            );
+            Ok(match env_var("GTFS_STATIC_ZIP") {
+                Some(path) => source.set_route_names(route_names(File::open(path)?)?),
+                None => source,
+            })
+        }
+
+        async fn fetch(&self) -> Result<FeedMessage> {
+            let feed = reqwest::Client::new()
+                .get(&self.url)
+                .header(USER_AGENT, "tidbyt")
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?;
+            Ok(FeedMessage::decode(feed)?)
+        }
+    }
+
+    #[async_trait]
+    impl TransitSource for GtfsRealtimeSource {
+        fn name(&self) -> &str {
+            "gtfs-rt"
+        }
+
+        async fn stop_visits(
+            &self,
+            _config: &NextBusesConfig,
+            stop: &BusStop,
+            _clock: &dyn Clock,
+        ) -> Result<Vec<ExpectedBusArrival>> {
+            // The feed covers every stop, so it's only fetched once.
+            let feed = self.feed.get_or_try_init(|| self.fetch()).await?;
+            Ok(departures(feed, &stop.naptan_code, &self.route_names))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::clock::clock::FixedClock;
+        use crate::next_buses::select_arrivals;
+        use rstest::*;
+        use std::io::Cursor;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        #[fixture]
+        fn trip_updates() -> &'static [u8] {
+            include_bytes!("../fixtures/gtfs_rt_trip_updates.pb")
+        }
+
+        fn feed(bytes: &[u8]) -> FeedMessage {
+            FeedMessage::decode(bytes).unwrap()
+        }
+
+        // One trip on route R61, calling at the stop in five minutes.
+        fn trip_update(trip: TripScheduleRelationship, stop: ScheduleRelationship) -> FeedMessage {
+            let update = StopTimeUpdate {
+                stop_id: Some("45242629".to_string()),
+                arrival: Some(StopTimeEvent {
+                    delay: None,
+                    time: Some(clock().now().timestamp() + 5 * 60),
+                }),
+                departure: None,
+                schedule_relationship: Some(stop.into()),
+            };
+            FeedMessage {
+                entity: vec![FeedEntity {
+                    id: "1".to_string(),
+                    trip_update: Some(TripUpdate {
+                        trip: TripDescriptor {
+                            trip_id: Some("T1".to_string()),
+                            schedule_relationship: Some(trip.into()),
+                            route_id: Some("R61".to_string()),
+                        },
+                        stop_time_update: vec![update],
+                    }),
+                }],
+            }
+        }
+
+        fn clock() -> FixedClock {
+            FixedClock(DateTime::parse_from_rfc3339("2024-03-09T15:21:17Z").unwrap())
+        }
+
+        #[rstest]
+        pub fn parses_trip_updates_for_stop(trip_updates: &[u8]) {
+            let now = clock().now();
+            let arrivals = departures(&feed(trip_updates), "45242629", &HashMap::new());
+            let summary: Vec<_> = arrivals
+                .iter()
+                .map(|arrival| (arrival.line.as_str(), arrival.expected_time - now))
+                .collect();
+            // The skipped stop on the 17 is left out.
+            assert_eq!(
+                summary,
+                vec![
+                    ("60", Duration::minutes(-2)),
+                    ("60A", Duration::seconds(5 * 60 + 30)),
+                    ("61", Duration::minutes(14)),
+                ]
+            );
+            assert_eq!(
+                arrivals[1].aimed_time,
+                Some(now + Duration::seconds(3 * 60 + 30))
+            );
+            assert_eq!(arrivals[2].aimed_time, None);
+        }
+
+        #[rstest]
+        pub fn selects_catchable_arrivals(trip_updates: &[u8]) {
+            let config = NextBusesConfig::new(vec![BusStop {
+                naptan_code: "45242629".to_string(),
+                minutes_away: 0,
+            }]);
+            let arrivals = select_arrivals(
+                departures(&feed(trip_updates), "45242629", &HashMap::new()),
+                &config,
+                &config.stops[0],
+                &clock(),
+            );
+            let lines: Vec<_> = arrivals
+                .iter()
+                .map(|arrival| arrival.line.as_str())
+                .collect();
+            assert_eq!(lines, vec!["60A", "61"]);
+            assert_eq!(arrivals[0].stop.as_deref(), Some("45242629"));
+        }
+
+        #[rstest]
+        #[case(
+            TripScheduleRelationship::Scheduled,
+            ScheduleRelationship::Scheduled,
+            Some(None)
+        )]
+        #[case(
+            TripScheduleRelationship::Canceled,
+            ScheduleRelationship::Skipped,
+            Some(Some(DepartureStatus::Cancelled))
+        )]
+        #[case(
+            TripScheduleRelationship::Deleted,
+            ScheduleRelationship::Scheduled,
+            None
+        )]
+        pub fn marks_cancelled_trips(
+            #[case] trip: TripScheduleRelationship,
+            #[case] stop: ScheduleRelationship,
+            #[case] expected: Option<Option<DepartureStatus>>,
+        ) {
+            let arrivals = departures(&trip_update(trip, stop), "45242629", &HashMap::new());
+            assert_eq!(
+                arrivals.first().map(|arrival| arrival.departure_status),
+                expected
+            );
+        }
+
+        #[rstest]
+        pub fn names_routes_from_the_timetable() {
+            let names =
+                route_names(Cursor::new(include_bytes!("../fixtures/gtfs_static.zip"))).unwrap();
+            let feed = trip_update(
+                TripScheduleRelationship::Scheduled,
+                ScheduleRelationship::Scheduled,
+            );
+            let arrivals = departures(&feed, "45242629", &names);
+            assert_eq!(arrivals[0].line, "61");
+        }
+
+        #[tokio::test]
+        pub async fn fetches_the_feed_once_for_all_stops() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/", listener.local_addr().unwrap());
+            let requests = Arc::new(AtomicUsize::new(0));
+            let served = requests.clone();
+            tokio::spawn(async move {
+                let body = include_bytes!("../fixtures/gtfs_rt_trip_updates.pb");
+                loop {
+                    let (mut socket, _) = listener.accept().await.unwrap();
+                    served.fetch_add(1, Ordering::SeqCst);
+                    let mut request = [0; 1024];
+                    let _ = socket.read(&mut request).await;
+                    let header = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    socket.write_all(header.as_bytes()).await.unwrap();
+                    socket.write_all(body).await.unwrap();
+                }
+            });
+            let config = NextBusesConfig::new(vec![]);
+            let source = GtfsRealtimeSource::new(url);
+            for naptan_code in ["45242629", "45242630"] {
+                let stop = BusStop {
+                    naptan_code: naptan_code.to_string(),
+                    minutes_away: 0,
+                };
+                source.stop_visits(&config, &stop, &clock()).await.unwrap();
+            }
+            assert_eq!(requests.load(Ordering::SeqCst), 1);
+        }
+    }
+}
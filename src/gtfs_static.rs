@@ -0,0 +1,470 @@
+pub mod gtfs_static {
+    use crate::clock::clock::Clock;
+    use crate::next_buses::{BusStop, ExpectedBusArrival, NextBusesConfig};
+    use crate::transit_source::transit_source::TransitSource;
+    use anyhow::{anyhow, Result};
+    use async_trait::async_trait;
+    use chrono::{DateTime, Datelike, Duration, FixedOffset, NaiveDate, TimeZone, Weekday};
+    use chrono_tz::Tz;
+    use once_cell::sync::OnceCell;
+    use serde::de::DeserializeOwned;
+    use serde::Deserialize;
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::{Read, Seek};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use zip::ZipArchive;
+
+    #[derive(Debug, Deserialize)]
+    struct Agency {
+        agency_timezone: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Stop {
+        stop_id: String,
+        #[serde(default)]
+        stop_code: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Route {
+        route_id: String,
+        #[serde(default)]
+        route_short_name: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Trip {
+        route_id: String,
+        service_id: String,
+        trip_id: String,
+        #[serde(default)]
+        trip_headsign: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct StopTime {
+        trip_id: String,
+        #[serde(default)]
+        arrival_time: Option<String>,
+        #[serde(default)]
+        departure_time: Option<String>,
+        stop_id: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Calendar {
+        service_id: String,
+        monday: u8,
+        tuesday: u8,
+        wednesday: u8,
+        thursday: u8,
+        friday: u8,
+        saturday: u8,
+        sunday: u8,
+        start_date: String,
+        end_date: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct CalendarDate {
+        service_id: String,
+        date: String,
+        exception_type: u8,
+    }
+
+    impl Calendar {
+        fn runs_on(&self, date: NaiveDate) -> bool {
+            let runs_on_weekday = match date.weekday() {
+                Weekday::Mon => self.monday,
+                Weekday::Tue => self.tuesday,
+                Weekday::Wed => self.wednesday,
+                Weekday::Thu => self.thursday,
+                Weekday::Fri => self.friday,
+                Weekday::Sat => self.saturday,
+                Weekday::Sun => self.sunday,
+            } == 1;
+            runs_on_weekday
+                && parse_date(&self.start_date).is_ok_and(|start| start <= date)
+                && parse_date(&self.end_date).is_ok_and(|end| date <= end)
+        }
+    }
+
+    fn parse_date(date: &str) -> Result<NaiveDate> {
+        Ok(NaiveDate::parse_from_str(date, "%Y%m%d")?)
+    }
+
+    /// GTFS times are "HH:MM:SS" from the start of the service day, and
+    /// go past 24:00 for trips that run after midnight.
+    fn parse_time(time: &str) -> Result<Duration> {
+        let mut parts = time.trim().splitn(3, ':').map(str::parse::<i64>);
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(hours)), Some(Ok(minutes)), Some(Ok(seconds))) => {
+                Ok(Duration::seconds(hours * 3600 + minutes * 60 + seconds))
+            }
+            _ => Err(anyhow!("Invalid GTFS time {}", time)),
+        }
+    }
+
+    fn read_table<R: Read + Seek, T: DeserializeOwned>(
+        archive: &mut ZipArchive<R>,
+        name: &str,
+    ) -> Result<Vec<T>> {
+        let file = archive.by_name(name)?;
+        Ok(csv::Reader::from_reader(file)
+            .deserialize()
+            .collect::<Result<_, _>>()?)
+    }
+
+    // calendar.txt and calendar_dates.txt are each optional, as long as
+    // a feed has one of them.
+    fn read_optional_table<R: Read + Seek, T: DeserializeOwned>(
+        archive: &mut ZipArchive<R>,
+        name: &str,
+    ) -> Result<Vec<T>> {
+        if archive.file_names().any(|file| file == name) {
+            read_table(archive, name)
+        } else {
+            Ok(vec![])
+        }
+    }
+
+    // Routes by their `route_id`, named as riders know them.
+    fn read_route_names<R: Read + Seek>(
+        archive: &mut ZipArchive<R>,
+    ) -> Result<HashMap<String, String>> {
+        Ok(read_optional_table::<_, Route>(archive, "routes.txt")?
+            .into_iter()
+            .filter_map(|route| Some((route.route_id, route.route_short_name?)))
+            .collect())
+    }
+
+    /// The short names of a GTFS static feed's routes, by `route_id`,
+    /// for feeds that only give the id.
+    pub fn route_names<R: Read + Seek>(reader: R) -> Result<HashMap<String, String>> {
+        read_route_names(&mut ZipArchive::new(reader)?)
+    }
+
+    /// The scheduled departures from the configured stops, read out of
+    /// a GTFS static feed.
+    #[derive(Debug)]
+    pub struct GtfsTimetable {
+        timezone: Tz,
+        departures: Vec<ScheduledDeparture>,
+        calendars: HashMap<String, Calendar>,
+        // Services added (true) or removed (false) on particular dates.
+        exceptions: HashMap<(String, NaiveDate), bool>,
+    }
+
+    #[derive(Debug)]
+    struct ScheduledDeparture {
+        // The configured code of the stop this leaves from.
+        naptan_code: String,
+        // GTFS feeds in Britain use ATCO codes as stop ids.
+        stop_id: String,
+        line: String,
+        direction: Option<String>,
+        service_id: String,
+        time: Duration,
+    }
+
+    impl GtfsTimetable {
+        /// Reads the timetable for `naptan_codes`, which can each match
+        /// either a GTFS `stop_id` or `stop_code`. Only the stop times for
+        /// those stops are kept, since national feeds have millions of them.
+        pub fn read<R: Read + Seek>(reader: R, naptan_codes: &[String]) -> Result<GtfsTimetable> {
+            let mut archive = ZipArchive::new(reader)?;
+
+            let timezone = read_table::<_, Agency>(&mut archive, "agency.txt")?
+                .first()
+                .map(|agency| agency.agency_timezone.parse::<Tz>())
+                .transpose()
+                .map_err(|err| anyhow!("Invalid agency timezone: {}", err))?
+                .unwrap_or(Tz::UTC);
+
+            let stop_ids: HashMap<String, String> =
+                read_table::<_, Stop>(&mut archive, "stops.txt")?
+                    .into_iter()
+                    .filter_map(|stop| {
+                        let naptan_code = naptan_codes.iter().find(|code| {
+                            **code == stop.stop_id || stop.stop_code.as_ref() == Some(*code)
+                        })?;
+                        Some((stop.stop_id, naptan_code.clone()))
+                    })
+                    .collect();
+
+            let route_names = read_route_names(&mut archive)?;
+
+            let trips: HashMap<String, Trip> = read_table::<_, Trip>(&mut archive, "trips.txt")?
+                .into_iter()
+                .map(|trip| (trip.trip_id.clone(), trip))
+                .collect();
+
+            let mut departures = vec![];
+            // ZipFile borrows the archive until it is dropped.
+            {
+                let stop_times = archive.by_name("stop_times.txt")?;
+                for stop_time in csv::Reader::from_reader(stop_times).deserialize() {
+                    let stop_time: StopTime = stop_time?;
+                    let (Some(naptan_code), Some(trip), Some(time)) = (
+                        stop_ids.get(&stop_time.stop_id),
+                        trips.get(&stop_time.trip_id),
+                        stop_time.departure_time.or(stop_time.arrival_time),
+                    ) else {
+                        continue;
+                    };
+                    departures.push(ScheduledDeparture {
+                        naptan_code: naptan_code.clone(),
+                        stop_id: stop_time.stop_id.clone(),
+                        line: route_names
+                            .get(&trip.route_id)
+                            .unwrap_or(&trip.route_id)
+                            .clone(),
+                        direction: trip.trip_headsign.clone(),
+                        service_id: trip.service_id.clone(),
+                        time: parse_time(&time)?,
+                    });
+                }
+            }
+
+            let calendars = read_optional_table::<_, Calendar>(&mut archive, "calendar.txt")?
+                .into_iter()
+                .map(|calendar| (calendar.service_id.clone(), calendar))
+                .collect();
+
+            let exceptions =
+                read_optional_table::<_, CalendarDate>(&mut archive, "calendar_dates.txt")?
+                    .into_iter()
+                    .map(|exception| {
+                        Ok((
+                            (exception.service_id, parse_date(&exception.date)?),
+                            exception.exception_type == 1,
+                        ))
+                    })
+                    .collect::<Result<_>>()?;
+
+            Ok(GtfsTimetable {
+                timezone,
+                departures,
+                calendars,
+                exceptions,
+            })
+        }
+
+        fn runs_on(&self, service_id: &str, date: NaiveDate) -> bool {
+            match self.exceptions.get(&(service_id.to_string(), date)) {
+                Some(added) => *added,
+                None => self
+                    .calendars
+                    .get(service_id)
+                    .is_some_and(|calendar| calendar.runs_on(date)),
+            }
+        }
+
+        /// Every timetabled departure from `naptan_code`, from yesterday's
+        /// service day (for trips running past midnight) to tomorrow's,
+        /// soonest first.
+        pub fn departures(&self, naptan_code: &str, clock: &dyn Clock) -> Vec<ExpectedBusArrival> {
+            let today = clock.now().with_timezone(&self.timezone).date_naive();
+            let mut arrivals: Vec<ExpectedBusArrival> = [-1, 0, 1]
+                .into_iter()
+                .filter_map(|days| today.checked_add_signed(Duration::days(days)))
+                .flat_map(|date| {
+                    self.departures
+                        .iter()
+                        .filter(move |departure| departure.naptan_code == naptan_code)
+                        .filter(move |departure| self.runs_on(&departure.service_id, date))
+                        .filter_map(move |departure| {
+                            let time = self.service_day_start(date)? + departure.time;
+                            Some(ExpectedBusArrival {
+                                line: departure.line.clone(),
+                                direction: departure.direction.clone(),
+                                stop: None,
+                                atco_code: Some(departure.stop_id.clone()),
+                                walking_minutes: 0,
+                                expected_time: time,
+                                aimed_time: Some(time),
+                                real_time: false,
+                                departure_status: None,
+                                operator: None,
+                                vehicle_mode: None,
+                                recorded_at: None,
+                                stale: false,
+                            })
+                        })
+                })
+                .collect();
+            arrivals.sort_by_key(|arrival| arrival.expected_time);
+            arrivals
+        }
+
+        // GTFS counts from noon minus 12 hours rather than midnight, so
+        // that times stay right on the days the clocks change.
+        fn service_day_start(&self, date: NaiveDate) -> Option<DateTime<FixedOffset>> {
+            let noon = self
+                .timezone
+                .from_local_datetime(&date.and_hms_opt(12, 0, 0)?)
+                .earliest()?;
+            Some((noon - Duration::hours(12)).fixed_offset())
+        }
+    }
+
+    /// Timetabled departures from a local GTFS static zip, for when
+    /// there is no live data to be had.
+    #[derive(Clone, Debug)]
+    pub struct GtfsStaticSource {
+        pub path: PathBuf,
+        // Read on first use, for every configured stop at once.
+        timetable: Arc<OnceCell<GtfsTimetable>>,
+    }
+
+    impl GtfsStaticSource {
+        pub fn new(path: PathBuf) -> GtfsStaticSource {
+            GtfsStaticSource {
+                path,
+                timetable: Arc::new(OnceCell::new()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl TransitSource for GtfsStaticSource {
+        fn name(&self) -> &str {
+            "gtfs-static"
+        }
+
+        async fn stop_visits(
+            &self,
+            config: &NextBusesConfig,
+            stop: &BusStop,
+            clock: &dyn Clock,
+        ) -> Result<Vec<ExpectedBusArrival>> {
+            let path = self.path.clone();
+            let naptan_codes: Vec<String> = config
+                .stops
+                .iter()
+                .map(|stop| stop.naptan_code.clone())
+                .collect();
+            let cell = self.timetable.clone();
+            // Unzipping and reading the CSVs is slow, blocking work.
+            tokio::task::spawn_blocking(move || {
+                cell.get_or_try_init(|| GtfsTimetable::read(File::open(path)?, &naptan_codes))
+                    .map(|_| ())
+            })
+            .await??;
+            let timetable = self
+                .timetable
+                .get()
+                .ok_or_else(|| anyhow!("GTFS timetable was not read"))?;
+            Ok(timetable.departures(&stop.naptan_code, clock))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::clock::clock::FixedClock;
+        use rstest::*;
+        use std::io::Cursor;
+
+        #[fixture]
+        fn timetable() -> GtfsTimetable {
+            let feed = include_bytes!("../fixtures/gtfs_static.zip");
+            GtfsTimetable::read(Cursor::new(feed), &["45242629".to_string()]).unwrap()
+        }
+
+        fn departures_at(timetable: &GtfsTimetable, now: &str) -> Vec<(String, String)> {
+            let clock = FixedClock(DateTime::parse_from_rfc3339(now).unwrap());
+            timetable
+                .departures("45242629", &clock)
+                .into_iter()
+                .filter(|arrival| arrival.expected_time >= clock.now())
+                .map(|arrival| (arrival.line, arrival.expected_time.to_rfc3339()))
+                .collect()
+        }
+
+        #[rstest]
+        #[case("01:10:00", Duration::minutes(70))]
+        #[case("25:10:00", Duration::minutes(25 * 60 + 10))]
+        pub fn parses_times_past_midnight(#[case] time: &str, #[case] expected: Duration) {
+            assert_eq!(parse_time(time).unwrap(), expected);
+        }
+
+        #[rstest]
+        pub fn uses_calendar_exceptions(timetable: GtfsTimetable) {
+            // 9 March 2024 is a Saturday, but runs the weekday timetable.
+            assert_eq!(
+                departures_at(&timetable, "2024-03-09T15:21:17Z"),
+                vec![
+                    ("60A".to_string(), "2024-03-09T15:26:00+00:00".to_string()),
+                    ("61".to_string(), "2024-03-09T15:40:00+00:00".to_string()),
+                ]
+            );
+        }
+
+        #[rstest]
+        pub fn includes_trips_from_the_previous_service_day(timetable: GtfsTimetable) {
+            assert_eq!(
+                departures_at(&timetable, "2024-03-09T01:00:00Z")[0],
+                ("N60".to_string(), "2024-03-09T01:10:00+00:00".to_string())
+            );
+        }
+
+        #[rstest]
+        #[tokio::test]
+        pub async fn reads_the_timetable_once_for_every_stop() {
+            let path = std::env::temp_dir().join(format!(
+                "tidbyt-rs-test-{}-gtfs_static.zip",
+                std::process::id()
+            ));
+            std::fs::write(&path, include_bytes!("../fixtures/gtfs_static.zip")).unwrap();
+            let source = GtfsStaticSource::new(path.clone());
+            let config = NextBusesConfig::new(
+                ["45242629", "45242630"]
+                    .into_iter()
+                    .map(|naptan_code| BusStop {
+                        naptan_code: naptan_code.to_string(),
+                        minutes_away: 0,
+                    })
+                    .collect(),
+            );
+            let clock = FixedClock(DateTime::parse_from_rfc3339("2024-03-09T15:11:00Z").unwrap());
+            let first = source
+                .stop_arrivals(&config, &config.stops[0], &clock)
+                .await
+                .unwrap();
+            // The second stop comes from what was read for the first.
+            std::fs::remove_file(&path).unwrap();
+            let second = source
+                .stop_arrivals(&config, &config.stops[1], &clock)
+                .await
+                .unwrap();
+            assert_eq!(first[0].line, "60A");
+            assert_eq!(
+                first[0].expected_time.to_rfc3339(),
+                "2024-03-09T15:26:00+00:00"
+            );
+            assert_eq!(
+                second
+                    .iter()
+                    .map(|arrival| arrival.expected_time.to_rfc3339())
+                    .collect::<Vec<_>>(),
+                vec!["2024-03-09T15:20:00+00:00"]
+            );
+        }
+
+        #[rstest]
+        pub fn flags_departures_as_scheduled(timetable: GtfsTimetable) {
+            let clock = FixedClock(DateTime::parse_from_rfc3339("2024-03-09T15:21:17Z").unwrap());
+            let arrivals = timetable.departures("45242629", &clock);
+            assert!(!arrivals.is_empty());
+            assert!(arrivals
+                .iter()
+                .all(|arrival| !arrival.real_time && arrival.aimed_time.is_some()));
+            assert_eq!(arrivals[0].direction.as_deref(), Some("Easterhouse"));
+            assert_eq!(arrivals[0].atco_code.as_deref(), Some("6090235412"));
+        }
+    }
+}
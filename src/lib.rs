@@ -1,23 +1,79 @@
 pub mod adjusted_color;
+pub mod arrivals_cache;
+pub mod clock;
+pub mod departure_time;
 pub mod draw_buffer;
+pub mod font;
+pub mod gtfs_realtime;
+pub mod gtfs_static;
+pub mod line_colors;
 
 use anyhow::{anyhow, Context, Error, Result};
 use std::borrow::Cow;
+use std::str::FromStr;
 pub mod next_buses;
 pub mod pusher;
+pub mod siri_request;
+pub mod situation_exchange;
+pub mod tfl;
+pub mod transit_source;
 
 use crate::draw_buffer::draw_buffer::get_rgba;
-use crate::next_buses::get_next_buses;
-use adjusted_color::adjusted_color::adjusted_color;
-use chrono::{prelude::*, Locale};
+use crate::next_buses::{
+    env_number, env_var, get_next_buses, group_arrivals, ArrivalGroup, ExpectedBusArrival,
+    NextBusesConfig, SiriError,
+};
+use adjusted_color::adjusted_color::{adjusted_color, contrasting_color};
+use chrono::prelude::*;
+use chrono::Duration;
 use chrono_tz::Tz;
+use clock::clock::{Clock, FixedClock, SystemClock};
+use departure_time::departure_time::{group_times, punctuality_color, TimeDisplay};
+use font::font::Font;
+use line_colors::line_colors::LineColors;
 use pusher::pusher::push;
 use raqote::*;
+use situation_exchange::situation_exchange::{ticker_text, SituationExchangeClient};
 use webp::{AnimEncoder, AnimFrame, WebPConfig};
 
 // Built in 2px of buffer.
 const WIDTH: i64 = 61;
 
+// Default time each animation frame is shown for, in milliseconds.
+const FRAME_DELAY: u32 = 50;
+
+// Space between a badge's edge and its text.
+const BADGE_PADDING: f32 = 1.0;
+
+// Rows on each page of a board with more departures than fit.
+const ROWS_PER_PAGE: usize = 3;
+
+// Frames each page is shown for by default, about four seconds.
+const PAGE_FRAMES: u32 = 80;
+
+// Frames a slide from one page to the next takes.
+const SLIDE_FRAMES: u32 = 8;
+
+// Frames a blinking widget spends in each state.
+const BLINK_FRAMES: u32 = 10;
+
+// Disruption tickers are drawn in amber, like other warnings.
+const TICKER_COLOR: &str = "#fa0";
+
+// What a row's time blinks to when it's time to leave for that bus.
+const LEAVE_NOW_COLOR: &str = "#0f0";
+
+// Directions are drawn small and dim, so the times stand out.
+const DIRECTION_COLOR: &str = "#aaa";
+const DIRECTION_FONT: Font = Font::CgPixel3x5;
+
+// Space either side of a row's direction.
+const DIRECTION_GAP: f32 = 2.0;
+
+// Disruptions are extra, so the board doesn't wait long for them.
+const TICKER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[allow(dead_code)]
 #[derive(Copy, Clone)]
 enum TextAlign {
     Left,
@@ -27,12 +83,19 @@ enum TextAlign {
 #[derive(Debug)]
 pub struct RenderArgs {
     debug: Option<String>,
+    // The retry loop lives in main, which reads it from its own args.
+    #[allow(dead_code)]
     retry: Option<u64>,
+    at: Option<DateTime<FixedOffset>>,
 }
 
 impl RenderArgs {
-    pub fn new(debug: Option<String>, retry: Option<u64>) -> Self {
-        Self { debug, retry }
+    pub fn new(
+        debug: Option<String>,
+        retry: Option<u64>,
+        at: Option<DateTime<FixedOffset>>,
+    ) -> Self {
+        Self { debug, retry, at }
     }
 }
 
@@ -42,37 +105,28 @@ fn draw_text(
     in_start: Point,
     color: &Source,
     align: TextAlign,
+    font: Font,
 ) -> Result<()> {
-    let tom_thumb = include_bytes!("../fonts/tb-8.bdf");
-    let font: bdf::Font = bdf::read(&tom_thumb[..])?;
+    let glyphs = font.glyphs()?;
     let mut start = in_start;
     let chars: Vec<char> = match align {
         TextAlign::Left => text.chars().collect(),
         TextAlign::Right => text.chars().rev().collect(),
     };
     for c in chars {
-        let glyph = font.glyphs().get(&c).context("Could not get glyph")?;
-
-        // The tom-thumb font is monospace but some of the characters
-        // don't take up the full bounding box. Offset them so that
-        // they sit at the center of their bits.
-
-        for px in glyph.pixels() {
-            let x = px.0 .0;
-            let y = px.0 .1;
-            let white = px.1;
-            if white {
-                dt.fill_rect(
-                    start.x + x as f32 + 0 as f32,
-                    start.y + y as f32 + 0 as f32,
-                    1.,
-                    1.,
-                    color,
-                    &DrawOptions::new(),
-                )
-            }
+        let glyph = glyphs.get(c).context("Could not get glyph")?;
+
+        for (x, y) in &glyph.pixels {
+            dt.fill_rect(
+                start.x + *x as f32,
+                start.y + *y as f32,
+                1.,
+                1.,
+                color,
+                &DrawOptions::new(),
+            )
         }
-        start.x += advance(c)
+        start.x += glyph.advance
             * match align {
                 TextAlign::Left => 1.0,
                 TextAlign::Right => -1.0,
@@ -81,54 +135,489 @@ fn draw_text(
     Ok(())
 }
 
-fn advance(c: char) -> f32 {
-    if c == ' ' {
-        return 2.0;
-    }
-    let tom_thumb = include_bytes!("../fonts/tb-8.bdf");
-    let font = bdf::read(&tom_thumb[..]);
-    let w = font.map(|f| f.glyphs().get(&c).map(|g| g.width()));
-    w.unwrap_or(Some(0)).unwrap_or(0) as f32
-}
-
 trait Widget: Send {
     // Gets the width of the given widget
     fn measure(&self) -> Point;
     fn frame_count(&self) -> u32;
-    fn render(&self, dt: &mut DrawTarget, point: Point, frame: u32) -> Result<(), Error>;
+    // How long the given frame is shown for, in milliseconds.
+    fn frame_delay(&self, _frame: u32) -> u32 {
+        FRAME_DELAY
+    }
+    fn render(
+        &self,
+        dt: &mut DrawTarget,
+        point: Point,
+        frame: u32,
+        clock: &dyn Clock,
+    ) -> Result<(), Error>;
 }
 
 #[derive(Clone, Debug)]
 pub struct TextWidget<'a> {
     text: Cow<'static, str>,
     color: &'a str,
+    font: Font,
+    // Draw a line through the middle of the text.
+    strike: bool,
 }
 
 impl<'a> TextWidget<'a> {
     fn new(text: Cow<'static, str>, color: &'a str) -> Result<TextWidget<'a>, Error> {
-        Ok::<TextWidget<'a>, Error>(TextWidget { text, color })
+        Ok::<TextWidget<'a>, Error>(TextWidget {
+            text,
+            color,
+            font: Font::default(),
+            strike: false,
+        })
+    }
+    fn set_font(mut self, font: Font) -> TextWidget<'a> {
+        self.font = font;
+        self
+    }
+    fn set_strike(mut self, strike: bool) -> TextWidget<'a> {
+        self.strike = strike;
+        self
     }
 }
 
 impl<'a> Widget for TextWidget<'a> {
     fn measure(&self) -> Point {
-        let width: f32 = self.text.chars().map(advance).sum();
-        Point::new(width, 8.0)
+        match self.font.glyphs() {
+            Ok(glyphs) => Point::new(glyphs.measure(&self.text), glyphs.line_height),
+            Err(_) => Point::new(0.0, 0.0),
+        }
     }
     fn frame_count(&self) -> u32 {
         1
     }
-    fn render(&self, dt: &mut DrawTarget, point: Point, _frame: u32) -> Result<(), Error> {
-        let color = adjusted_color(self.color)?;
-        draw_text(dt, &self.text, point, &color, TextAlign::Left)
+    fn render(
+        &self,
+        dt: &mut DrawTarget,
+        point: Point,
+        _frame: u32,
+        clock: &dyn Clock,
+    ) -> Result<(), Error> {
+        let color = adjusted_color(self.color, clock)?;
+        draw_text(dt, &self.text, point, &color, TextAlign::Left, self.font)?;
+        if self.strike {
+            let size = self.measure();
+            let y = (point.y + size.y / 2.).floor();
+            dt.fill_rect(point.x, y, size.x, 1., &color, &DrawOptions::new());
+        }
+        Ok(())
     }
 }
 
+/**
+ * Scrolls a text widget that is wider than its slot from
+ * one end to the other, pausing at each end.
+ */
+struct MarqueeWidget<'a> {
+    text: TextWidget<'a>,
+    width: f32,
+    // Pixels scrolled per frame.
+    speed: f32,
+    // Frames to hold at each end of the scroll.
+    pause: u32,
+}
+
+impl<'a> MarqueeWidget<'a> {
+    fn new(text: TextWidget<'a>, width: f32) -> Result<MarqueeWidget<'a>, Error> {
+        Ok::<MarqueeWidget<'a>, Error>(MarqueeWidget {
+            text,
+            width,
+            speed: 1.0,
+            pause: 10,
+        })
+    }
+    fn set_speed(mut self, speed: f32) -> MarqueeWidget<'a> {
+        self.speed = speed.max(1.0);
+        self
+    }
+    fn set_pause(mut self, pause: u32) -> MarqueeWidget<'a> {
+        self.pause = pause;
+        self
+    }
+    fn overflow(&self) -> f32 {
+        (self.text.measure().x - self.width).max(0.0)
+    }
+    fn scroll_frames(&self) -> u32 {
+        (self.overflow() / self.speed).ceil() as u32
+    }
+    fn offset(&self, frame: u32) -> f32 {
+        let scrolled = frame.saturating_sub(self.pause) as f32 * self.speed;
+        scrolled.min(self.overflow())
+    }
+}
+
+impl<'a> Widget for MarqueeWidget<'a> {
+    fn measure(&self) -> Point {
+        Point::new(self.width, self.text.measure().y)
+    }
+    fn frame_count(&self) -> u32 {
+        if self.overflow() == 0.0 {
+            1
+        } else {
+            self.pause * 2 + self.scroll_frames() + 1
+        }
+    }
+    fn render(
+        &self,
+        dt: &mut DrawTarget,
+        point: Point,
+        frame: u32,
+        clock: &dyn Clock,
+    ) -> Result<()> {
+        let size = self.measure();
+        dt.push_clip_rect(IntRect::new(
+            IntPoint::new(point.x as i32, point.y as i32),
+            IntPoint::new((point.x + size.x) as i32, (point.y + size.y) as i32),
+        ));
+        let offset = self.offset(frame % self.frame_count());
+        let result = self
+            .text
+            .render(dt, Point::new(point.x - offset, point.y), frame, clock);
+        dt.pop_clip();
+        result
+    }
+}
+
+/**
+ * How marquees on the board scroll, from `MARQUEE_SPEED` in pixels per
+ * frame and `MARQUEE_PAUSE` in frames held at each end.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Scroll {
+    speed: Option<f32>,
+    pause: Option<u32>,
+}
+
+impl Scroll {
+    fn from_env() -> Result<Scroll> {
+        Ok(Scroll {
+            speed: env_number("MARQUEE_SPEED")?,
+            pause: env_number("MARQUEE_PAUSE")?,
+        })
+    }
+    fn marquee<'a>(&self, text: TextWidget<'a>, width: f32) -> Result<MarqueeWidget<'a>, Error> {
+        let mut marquee = MarqueeWidget::new(text, width)?;
+        if let Some(speed) = self.speed {
+            marquee = marquee.set_speed(speed);
+        }
+        if let Some(pause) = self.pause {
+            marquee = marquee.set_pause(pause);
+        }
+        Ok(marquee)
+    }
+}
+
+/**
+ * Text on a filled badge with its corners knocked off, drawn in
+ * black or white depending on which stands out from the fill.
+ */
+struct BadgeWidget<'a> {
+    text: TextWidget<'a>,
+    color: String,
+}
+
+impl BadgeWidget<'static> {
+    fn new(text: Cow<'static, str>, color: &str) -> Result<BadgeWidget<'static>, Error> {
+        Ok(BadgeWidget {
+            text: TextWidget::new(text, contrasting_color(color)?)?,
+            color: color.to_string(),
+        })
+    }
+}
+
+impl<'a> Widget for BadgeWidget<'a> {
+    fn measure(&self) -> Point {
+        let text = self.text.measure();
+        Point::new(text.x + BADGE_PADDING * 2., text.y)
+    }
+    fn frame_count(&self) -> u32 {
+        1
+    }
+    fn render(
+        &self,
+        dt: &mut DrawTarget,
+        point: Point,
+        frame: u32,
+        clock: &dyn Clock,
+    ) -> Result<()> {
+        let size = self.measure();
+        let fill = adjusted_color(&self.color, clock)?;
+        // Two overlapping rectangles leave each corner pixel empty.
+        dt.fill_rect(
+            point.x + 1.,
+            point.y,
+            size.x - 2.,
+            size.y,
+            &fill,
+            &DrawOptions::new(),
+        );
+        dt.fill_rect(
+            point.x,
+            point.y + 1.,
+            size.x,
+            size.y - 2.,
+            &fill,
+            &DrawOptions::new(),
+        );
+        self.text.render(
+            dt,
+            Point::new(point.x + BADGE_PADDING, point.y),
+            frame,
+            clock,
+        )
+    }
+}
+
+/**
+ * Switches between two widgets every `period` frames, to draw
+ * the eye to something.
+ */
+struct BlinkWidget {
+    on: Box<dyn Widget>,
+    off: Box<dyn Widget>,
+    period: u32,
+}
+
+impl Widget for BlinkWidget {
+    fn measure(&self) -> Point {
+        self.on.measure()
+    }
+    fn frame_count(&self) -> u32 {
+        self.period * 2
+    }
+    fn frame_delay(&self, frame: u32) -> u32 {
+        self.on.frame_delay(frame).max(self.off.frame_delay(frame))
+    }
+    fn render(
+        &self,
+        dt: &mut DrawTarget,
+        point: Point,
+        frame: u32,
+        clock: &dyn Clock,
+    ) -> Result<()> {
+        if frame % (self.period * 2) < self.period {
+            self.on.render(dt, point, frame, clock)
+        } else {
+            self.off.render(dt, point, frame, clock)
+        }
+    }
+}
+
+/// How a paginated board moves from one page to the next.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+enum PageTransition {
+    Cut,
+    #[default]
+    Slide,
+}
+
+/// Parses "cut" or "slide".
+impl FromStr for PageTransition {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "cut" => Ok(PageTransition::Cut),
+            "slide" => Ok(PageTransition::Slide),
+            _ => Err(anyhow!("Unknown page transition {}", s)),
+        }
+    }
+}
+
+/**
+ * Shows each page in turn for `hold` frames, looping back to the
+ * first, with a dot per page underneath marking the current one.
+ */
+struct PagedWidget {
+    pages: Vec<Box<dyn Widget>>,
+    hold: u32,
+    transition: PageTransition,
+}
+
+impl PagedWidget {
+    fn new(pages: Vec<Box<dyn Widget>>) -> PagedWidget {
+        PagedWidget {
+            pages,
+            hold: PAGE_FRAMES,
+            transition: PageTransition::default(),
+        }
+    }
+    fn set_hold(mut self, hold: u32) -> PagedWidget {
+        self.hold = hold.max(1);
+        self
+    }
+    fn set_transition(mut self, transition: PageTransition) -> PagedWidget {
+        self.transition = transition;
+        self
+    }
+    fn page_height(&self) -> f32 {
+        self.pages
+            .iter()
+            .map(|page| page.measure().y)
+            .fold(0.0, f32::max)
+    }
+    // The page showing on `frame`, and how far through it that is.
+    fn page_at(&self, frame: u32) -> (usize, u32) {
+        let page = (frame / self.hold) as usize % self.pages.len().max(1);
+        (page, frame % self.hold)
+    }
+    // How far the next page has slid in, in pixels.
+    fn slide_offset(&self, local_frame: u32) -> f32 {
+        let frames = match self.transition {
+            PageTransition::Cut => return 0.0,
+            PageTransition::Slide => SLIDE_FRAMES.min(self.hold),
+        };
+        let start = self.hold - frames;
+        if local_frame < start {
+            return 0.0;
+        }
+        let progress = (local_frame - start + 1) as f32 / frames as f32;
+        (self.page_height() * progress).floor()
+    }
+}
+
+impl Widget for PagedWidget {
+    fn measure(&self) -> Point {
+        // Room for the page indicator below a pixel of space.
+        Point::new(WIDTH as f32, self.page_height() + 2.)
+    }
+    fn frame_count(&self) -> u32 {
+        self.pages.len() as u32 * self.hold
+    }
+    fn frame_delay(&self, frame: u32) -> u32 {
+        let (page, local_frame) = self.page_at(frame);
+        self.pages
+            .get(page)
+            .map(|page| page.frame_delay(local_frame))
+            .unwrap_or(FRAME_DELAY)
+    }
+    fn render(
+        &self,
+        dt: &mut DrawTarget,
+        point: Point,
+        frame: u32,
+        clock: &dyn Clock,
+    ) -> Result<()> {
+        if self.pages.is_empty() {
+            return Ok(());
+        }
+        let (page, local_frame) = self.page_at(frame);
+        let height = self.page_height();
+        let offset = self.slide_offset(local_frame);
+
+        dt.push_clip_rect(IntRect::new(
+            IntPoint::new(point.x as i32, point.y as i32),
+            IntPoint::new((point.x + WIDTH as f32) as i32, (point.y + height) as i32),
+        ));
+        let result = self.pages[page]
+            .render(
+                dt,
+                Point::new(point.x, point.y - offset),
+                local_frame,
+                clock,
+            )
+            .and_then(|_| {
+                if offset == 0.0 {
+                    return Ok(());
+                }
+                let next = &self.pages[(page + 1) % self.pages.len()];
+                next.render(
+                    dt,
+                    Point::new(point.x, point.y + height - offset),
+                    local_frame,
+                    clock,
+                )
+            });
+        dt.pop_clip();
+        result?;
+
+        // Right-aligned, a pixel per page with a pixel between each.
+        let current = adjusted_color("#fff", clock)?;
+        let other = adjusted_color("#444", clock)?;
+        let count = self.pages.len() as f32;
+        let left = point.x + WIDTH as f32 - (count * 2. - 1.);
+        for index in 0..self.pages.len() {
+            dt.fill_rect(
+                left + index as f32 * 2.,
+                point.y + height + 1.,
+                1.,
+                1.,
+                if index == page { &current } else { &other },
+                &DrawOptions::new(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Splits a board with more rows than fit on the display into pages.
+ */
+fn paginate(
+    board: VStack,
+    rows_per_page: usize,
+    transition: PageTransition,
+    hold: u32,
+) -> Box<dyn Widget> {
+    if board.items.len() <= rows_per_page {
+        return Box::new(board);
+    }
+    let gap = board.gap;
+    let mut rows = board.items.into_iter().peekable();
+    let mut pages: Vec<Box<dyn Widget>> = Vec::new();
+    while rows.peek().is_some() {
+        pages.push(Box::new(VStack {
+            items: rows.by_ref().take(rows_per_page).collect(),
+            gap,
+        }));
+    }
+    Box::new(
+        PagedWidget::new(pages)
+            .set_hold(hold)
+            .set_transition(transition),
+    )
+}
+
+/**
+ * Pages through the board, giving up its bottom row to a scrolling
+ * ticker when there is one.
+ */
+fn board_layout(
+    board: VStack,
+    ticker: Option<String>,
+    scroll: &Scroll,
+    transition: PageTransition,
+    hold: u32,
+) -> Result<Box<dyn Widget>, Error> {
+    // Alerts are free text, so leave out anything the font can't draw.
+    let glyphs = Font::default().glyphs()?;
+    let Some(ticker) = ticker
+        .map(|ticker| glyphs.drawable(&ticker))
+        .filter(|ticker| !ticker.trim().is_empty())
+    else {
+        return Ok(paginate(board, ROWS_PER_PAGE, transition, hold));
+    };
+    let gap = board.gap;
+    Ok(Box::new(VStack {
+        items: vec![
+            paginate(board, ROWS_PER_PAGE - 1, transition, hold),
+            Box::new(scroll.marquee(TextWidget::new(ticker.into(), TICKER_COLOR)?, WIDTH as f32)?),
+        ],
+        gap,
+    }))
+}
+
+#[allow(dead_code)]
 struct ChartWidget {
     data: Vec<u64>,
     height: i32,
 }
 
+#[allow(dead_code)]
 impl ChartWidget {
     fn new(data: &[u64]) -> Result<ChartWidget, anyhow::Error> {
         Ok::<ChartWidget, anyhow::Error>(ChartWidget {
@@ -145,7 +634,13 @@ impl Widget for ChartWidget {
     fn frame_count(&self) -> u32 {
         1
     }
-    fn render(&self, dt: &mut DrawTarget, point: Point, _frame: u32) -> Result<()> {
+    fn render(
+        &self,
+        dt: &mut DrawTarget,
+        point: Point,
+        _frame: u32,
+        clock: &dyn Clock,
+    ) -> Result<()> {
         if self.data.is_empty() {
             return Ok(());
         }
@@ -156,13 +651,16 @@ impl Widget for ChartWidget {
             if high {
                 h = 8.0;
             }
-            let color = adjusted_color(if high {
-                "#0ff"
-            } else if h > 1.0 {
-                "#eee"
-            } else {
-                "#555"
-            })?;
+            let color = adjusted_color(
+                if high {
+                    "#0ff"
+                } else if h > 1.0 {
+                    "#eee"
+                } else {
+                    "#555"
+                },
+                clock,
+            )?;
             dt.fill_rect(
                 pt.x,
                 pt.y + (self.height as f32) - h,
@@ -180,12 +678,14 @@ impl Widget for ChartWidget {
 /**
  * Horizontal stack
  */
+#[allow(dead_code)]
 struct HStack {
     items: Vec<Box<dyn Widget>>,
     gap: f32,
     expand: bool,
 }
 
+#[allow(dead_code)]
 impl HStack {
     fn set_gap(mut self, gap: f32) -> HStack {
         self.gap = gap;
@@ -214,11 +714,24 @@ impl Widget for HStack {
             .max()
             .unwrap_or(1)
     }
-    fn render(&self, dt: &mut DrawTarget, point: Point, frame: u32) -> Result<()> {
+    fn frame_delay(&self, frame: u32) -> u32 {
+        self.items
+            .iter()
+            .map(|item| item.frame_delay(frame))
+            .max()
+            .unwrap_or(FRAME_DELAY)
+    }
+    fn render(
+        &self,
+        dt: &mut DrawTarget,
+        point: Point,
+        frame: u32,
+        clock: &dyn Clock,
+    ) -> Result<()> {
         if self.items.is_empty() {
         } else if self.items.len() == 1 {
             if let Some(item) = self.items.first() {
-                item.render(dt, point, frame)?;
+                item.render(dt, point, frame, clock)?;
             }
         } else {
             let widths: Vec<u32> = self
@@ -230,7 +743,8 @@ impl Widget for HStack {
             let extra_room: i64 = WIDTH - total_content_size;
             let gap_count = (self.items.len() - 1) as i64;
             let mut spaces: Vec<f32> = Vec::new();
-            let space_between = extra_room / gap_count;
+            // Items too wide to fit are drawn touching, not overlapping.
+            let space_between = (extra_room / gap_count).max(0);
             spaces.resize(self.items.len() - 1, space_between as f32);
             let total_size_with_gaps = total_content_size + (gap_count * space_between);
             let remainder = WIDTH - total_size_with_gaps;
@@ -242,7 +756,7 @@ impl Widget for HStack {
             }
             let mut start_point = point;
             for (i, item) in self.items.iter().enumerate() {
-                item.render(dt, start_point, frame)?;
+                item.render(dt, start_point, frame, clock)?;
                 start_point.x = start_point.x + item.measure().x + spaces.get(i).unwrap_or(&0.0);
             }
         }
@@ -267,7 +781,9 @@ impl VStack {
 
 impl Widget for VStack {
     fn measure(&self) -> Point {
-        Point::new(WIDTH as f32, 5.0)
+        let heights: f32 = self.items.iter().map(|item| item.measure().y).sum();
+        let gaps = self.items.len().saturating_sub(1) as f32 * self.gap;
+        Point::new(WIDTH as f32, (heights + gaps).max(5.0))
     }
     fn frame_count(&self) -> u32 {
         self.items
@@ -276,10 +792,23 @@ impl Widget for VStack {
             .max()
             .unwrap_or(1)
     }
-    fn render(&self, dt: &mut DrawTarget, point: Point, frame: u32) -> Result<()> {
+    fn frame_delay(&self, frame: u32) -> u32 {
+        self.items
+            .iter()
+            .map(|item| item.frame_delay(frame))
+            .max()
+            .unwrap_or(FRAME_DELAY)
+    }
+    fn render(
+        &self,
+        dt: &mut DrawTarget,
+        point: Point,
+        frame: u32,
+        clock: &dyn Clock,
+    ) -> Result<()> {
         let mut start_point = point;
         for item in self.items.iter() {
-            item.render(dt, start_point, frame)?;
+            item.render(dt, start_point, frame, clock)?;
             start_point.y = start_point.y + item.measure().y + self.gap;
         }
         Ok(())
@@ -328,93 +857,455 @@ macro_rules! vstack {
     };
 }
 
-macro_rules! vstack {
-    ( $( $x:expr ),* ) => {
-        {
-            let mut temp_vec: Vec<Box<dyn Widget>> = Vec::new();
-            $(
-                if let Ok(z) = $x {
-                    temp_vec.push(Box::new(z));
-                }
-            )*
-            let res: Result<VStack, anyhow::Error> = Ok(VStack { items: temp_vec, gap: 0.0 });
-            res
-        }
+/**
+ * Where a bus is going, in small text scrolling through the `room`
+ * left between its line and time. Nothing when there's barely room.
+ */
+fn direction_label(
+    direction: &str,
+    room: f32,
+    scroll: &Scroll,
+) -> Result<Option<Box<dyn Widget>>, Error> {
+    let glyphs = DIRECTION_FONT.glyphs()?;
+    // Leave out anything the font can't draw, rather than fail the board.
+    let direction = glyphs.drawable(direction);
+    let width = room - DIRECTION_GAP * 2.;
+    if direction.trim().is_empty() || width < glyphs.measure("AB") {
+        return Ok(None);
+    }
+    let text = TextWidget::new(direction.into(), DIRECTION_COLOR)?.set_font(DIRECTION_FONT);
+    Ok(Some(Box::new(scroll.marquee(text, width)?)))
+}
+
+/**
+ * The line name, badged in its colour if it has one.
+ */
+fn line_label(
+    arrival: &ExpectedBusArrival,
+    line_colors: &LineColors,
+) -> Result<Box<dyn Widget>, Error> {
+    Ok(match line_colors.color_for(arrival) {
+        Some(color) => Box::new(BadgeWidget::new(arrival.line.to_string().into(), color)?),
+        None => Box::new(TextWidget::new(arrival.line.to_string().into(), "#fff")?),
+    })
+}
+
+/**
+ * A line name and its time, styled after `arrival`: badged, struck
+ * through when cancelled, and with its time blinking when it's time to
+ * leave. A `direction` goes between them, scrolling as it's told to.
+ */
+fn departure_row(
+    arrival: &ExpectedBusArrival,
+    time: String,
+    direction: Option<(&str, &Scroll)>,
+    leave_window: Option<Duration>,
+    line_colors: &LineColors,
+    clock: &dyn Clock,
+) -> Result<Box<dyn Widget>, Error> {
+    // Cancelled buses stay on the board so nobody waits for them, but
+    // struck through in red so they don't look like one to catch.
+    if arrival.is_cancelled() {
+        return Ok(Box::new(HStack {
+            items: vec![
+                Box::new(
+                    TextWidget::new(arrival.line.to_string().into(), "#f00")?.set_strike(true),
+                ),
+                Box::new(TextWidget::new(time.into(), "#f00")?.set_strike(true)),
+            ],
+            gap: 0.0,
+            expand: false,
+        }));
+    }
+    let line = line_label(arrival, line_colors)?;
+    let time_text = |color| TextWidget::new(time.clone().into(), color);
+    // Only the time blinks, so the line keeps its badge throughout.
+    let time: Box<dyn Widget> = match leave_window {
+        Some(window) if arrival.leave_within(window, clock) => Box::new(BlinkWidget {
+            on: Box::new(time_text(LEAVE_NOW_COLOR)?),
+            off: Box::new(time_text(punctuality_color(arrival))?),
+            period: BLINK_FRAMES,
+        }),
+        _ => Box::new(time_text(punctuality_color(arrival))?),
     };
+    let room = WIDTH as f32 - line.measure().x - time.measure().x;
+    let direction = match direction {
+        Some((direction, scroll)) => direction_label(direction, room, scroll)?,
+        None => None,
+    };
+    Ok(Box::new(HStack {
+        items: [Some(line), direction, Some(time)]
+            .into_iter()
+            .flatten()
+            .collect(),
+        gap: 0.0,
+        expand: false,
+    }))
+}
+
+fn arrival_row(
+    arrival: &ExpectedBusArrival,
+    timezone: &Tz,
+    time_display: TimeDisplay,
+    directions: Option<&Scroll>,
+    leave_window: Option<Duration>,
+    line_colors: &LineColors,
+    clock: &dyn Clock,
+) -> Result<Box<dyn Widget>, Error> {
+    let time = time_display.format(arrival, timezone, clock);
+    let direction = directions.and_then(|scroll| Some((arrival.direction.as_deref()?, scroll)));
+    departure_row(arrival, time, direction, leave_window, line_colors, clock)
+}
+
+/**
+ * A line with its next few times, e.g. "60A 3 12 27", styled after
+ * the first of them.
+ */
+fn group_row(
+    group: &ArrivalGroup,
+    directions: Option<&Scroll>,
+    leave_window: Option<Duration>,
+    line_colors: &LineColors,
+    clock: &dyn Clock,
+) -> Result<Box<dyn Widget>, Error> {
+    let first = group
+        .arrivals
+        .first()
+        .ok_or_else(|| anyhow!("No departures for line {}", group.line))?;
+    let room = WIDTH as f32 - line_label(first, line_colors)?.measure().x;
+    let times = fitting_times(&group.arrivals, room, clock)?;
+    // Only groups split by direction have just the one.
+    let direction = directions.and_then(|scroll| Some((group.direction.as_deref()?, scroll)));
+    departure_row(first, times, direction, leave_window, line_colors, clock)
+}
+
+/**
+ * As many of the next times as fit in `room` after a space, always
+ * keeping the first. Later ones are left off rather than drawn over
+ * the line.
+ */
+fn fitting_times(
+    arrivals: &[ExpectedBusArrival],
+    room: f32,
+    clock: &dyn Clock,
+) -> Result<String, Error> {
+    let glyphs = Font::default().glyphs()?;
+    let times = (1..=arrivals.len())
+        .rev()
+        .map(|count| group_times(&arrivals[..count], clock))
+        .find(|times| glyphs.measure(&format!(" {}", times)) <= room);
+    Ok(times.unwrap_or_else(|| group_times(arrivals.get(..1).unwrap_or_default(), clock)))
+}
+
+/**
+ * Stacks rows up as a board, or shows a message when there are none.
+ */
+fn rows_board(rows: Vec<Box<dyn Widget>>) -> Result<VStack, Error> {
+    if rows.is_empty() {
+        return message_board("No buses");
+    }
+    Ok(VStack {
+        items: rows,
+        gap: 0.0,
+    }
+    .set_gap(2.0))
+}
+
+/**
+ * One row per arrival, or a message when there are none to show.
+ * Times for buses it's time to leave for blink.
+ */
+fn departure_board(
+    arrivals: &[ExpectedBusArrival],
+    timezone: &Tz,
+    time_display: TimeDisplay,
+    directions: Option<&Scroll>,
+    leave_window: Option<Duration>,
+    line_colors: &LineColors,
+    clock: &dyn Clock,
+) -> Result<VStack, Error> {
+    let rows: Vec<Box<dyn Widget>> = arrivals
+        .iter()
+        .map(|arrival| {
+            arrival_row(
+                arrival,
+                timezone,
+                time_display,
+                directions,
+                leave_window,
+                line_colors,
+                clock,
+            )
+        })
+        .collect::<Result<_, Error>>()?;
+    rows_board(rows)
+}
+
+/**
+ * One row per line, for when a frequent line would otherwise fill
+ * the board.
+ */
+fn grouped_board(
+    groups: &[ArrivalGroup],
+    directions: Option<&Scroll>,
+    leave_window: Option<Duration>,
+    line_colors: &LineColors,
+    clock: &dyn Clock,
+) -> Result<VStack, Error> {
+    let rows: Vec<Box<dyn Widget>> = groups
+        .iter()
+        .map(|group| group_row(group, directions, leave_window, line_colors, clock))
+        .collect::<Result<_, Error>>()?;
+    rows_board(rows)
+}
+
+/**
+ * Disruptions affecting the board's stops or lines, run together for
+ * the ticker. Failing to look them up shouldn't cost the departures,
+ * so that's only logged.
+ */
+async fn disruption_ticker(
+    config: &NextBusesConfig,
+    arrivals: &[ExpectedBusArrival],
+    clock: &dyn Clock,
+) -> Option<String> {
+    let client = SituationExchangeClient::from_env()?;
+    let mut lines: Vec<String> = arrivals
+        .iter()
+        .map(|arrival| arrival.line.clone())
+        .collect();
+    lines.extend(config.filter.include_lines.iter().cloned());
+    // SIRI-SX usually names stops by ATCO code, which only some feeds
+    // give alongside the code the stop was configured with.
+    let mut stops: Vec<String> = config
+        .stops
+        .iter()
+        .map(|stop| stop.naptan_code.clone())
+        .collect();
+    stops.extend(
+        arrivals
+            .iter()
+            .filter_map(|arrival| arrival.atco_code.clone()),
+    );
+    stops.sort();
+    stops.dedup();
+    let situations = tokio::time::timeout(
+        TICKER_TIMEOUT,
+        client.get_situations(config, &stops, &lines, clock),
+    )
+    .await
+    .unwrap_or_else(|_| Err(anyhow!("Timed out")));
+    match situations {
+        Ok(situations) => ticker_text(&situations),
+        Err(err) => {
+            println!("Could not look up disruptions: {}", err);
+            None
+        }
+    }
+}
+
+/**
+ * A dot in the corner for when the board is showing cached
+ * departures because the live ones couldn't be fetched.
+ */
+fn draw_stale_marker(dt: &mut DrawTarget, point: Point, clock: &dyn Clock) -> Result<()> {
+    let color = adjusted_color("#fa0", clock)?;
+    dt.fill_rect(point.x, point.y, 2., 2., &color, &DrawOptions::new());
+    Ok(())
+}
+
+/**
+ * A board with a single line of text in place of any departures.
+ */
+fn message_board(message: &'static str) -> Result<VStack, Error> {
+    Ok(VStack {
+        items: vec![Box::new(TextWidget::new(message.into(), "#fff")?)],
+        gap: 0.0,
+    })
+}
+
+/**
+ * Collapses runs of identical frames into one longer frame, and turns
+ * each frame's delay into the cumulative timestamp it starts at. Also
+ * gives the timestamp the last frame ends at.
+ */
+fn timed_frames(frames: Vec<(Vec<u8>, u32)>) -> (Vec<(Vec<u8>, i32)>, i32) {
+    let mut merged: Vec<(Vec<u8>, u32)> = Vec::new();
+    for (rgba, delay) in frames {
+        match merged.last_mut() {
+            Some((last, last_delay)) if *last == rgba => *last_delay += delay,
+            _ => merged.push((rgba, delay)),
+        }
+    }
+
+    let mut timestamp = 0;
+    let timed = merged
+        .into_iter()
+        .map(|(rgba, delay)| {
+            let start = timestamp;
+            timestamp += delay as i32;
+            (rgba, start)
+        })
+        .collect();
+    (timed, timestamp)
+}
+
+/**
+ * The webp crate always ends an animation at timestamp 0, which leaves
+ * libwebp to guess how long the last frame is shown for. Writes the
+ * real duration into the last ANMF chunk of the encoded file instead.
+ * Still images have no ANMF chunks, and are left alone.
+ */
+fn set_last_frame_duration(webp: &mut [u8], duration: u32) -> Result<()> {
+    // After the RIFF header, each chunk is a FourCC, a little-endian
+    // size, and a payload padded to an even length.
+    let mut offset = 12;
+    let mut last_frame = None;
+    while offset + 8 <= webp.len() {
+        let size = u32::from_le_bytes(webp[offset + 4..offset + 8].try_into()?) as usize;
+        if &webp[offset..offset + 4] == b"ANMF" {
+            last_frame = Some(offset + 8);
+        }
+        offset += 8 + size + size % 2;
+    }
+    if let Some(payload) = last_frame {
+        // The duration is 24 bits, after the frame's 24-bit X, Y,
+        // width and height.
+        let duration = duration.min(0xff_ffff).to_le_bytes();
+        webp.get_mut(payload + 12..payload + 15)
+            .ok_or_else(|| anyhow!("Truncated ANMF chunk"))?
+            .copy_from_slice(&duration[..3]);
+    }
+    Ok(())
 }
 
 pub async fn render(args: RenderArgs) -> Result<()> {
-    let _local: DateTime<Local> = Local::now();
-    let _now: DateTime<FixedOffset> = _local.into();
+    let clock: Box<dyn Clock> = match args.at {
+        Some(at) => Box::new(FixedClock(at)),
+        None => Box::new(SystemClock),
+    };
     let timezone: Tz = dotenvy::var("OUTPUT_TIMEZONE")?.parse()?;
+    let time_display: TimeDisplay = match env_var("TIME_DISPLAY") {
+        Some(time_display) => time_display.parse()?,
+        None => TimeDisplay::default(),
+    };
+    // Minutes before you need to leave for a bus that its row starts blinking.
+    let leave_window = env_number("LEAVE_NOW_MINUTES")?.map(Duration::minutes);
+    let line_colors = LineColors::from_env()?;
+    let scroll = Scroll::from_env()?;
+    // Where each bus is going, scrolling between its line and time.
+    let directions = env_var("SHOW_DIRECTION")
+        .is_some_and(|v| v == "true")
+        .then_some(&scroll);
+    let page_transition: PageTransition = match env_var("PAGE_TRANSITION") {
+        Some(transition) => transition.parse()?,
+        None => PageTransition::default(),
+    };
+    let page_frames = match env_number::<u32>("PAGE_SECONDS")? {
+        Some(seconds) => {
+            seconds
+                .checked_mul(1000)
+                .ok_or_else(|| anyhow!("PAGE_SECONDS is too long: {}", seconds))?
+                / FRAME_DELAY
+        }
+        None => PAGE_FRAMES,
+    };
     let width = 64i32;
     let height = 32i32;
     let mut config = WebPConfig::new().map_err(|_s| anyhow!("WebPConfig failed"))?;
     config.lossless = 1;
     let mut encoder = AnimEncoder::new(width as u32, height as u32, &config);
 
-    let next_buses = get_next_buses().await?;
-
-    let [widget1, widget2, widget3, widget4, widget5, widget6]: [TextWidget; 6] = next_buses
-        .iter()
-        .flat_map(|arrival| {
-            vec![
-                TextWidget::new(arrival.line.to_string().into(), "#fff").unwrap(),
-                TextWidget::new(
-                    format!(
-                        "{}",
-                        arrival
-                            .expected_time
-                            .with_timezone(&timezone)
-                            .format_localized("%H:%M", Locale::en_GB)
-                    )
-                    .into(),
-                    "#fff",
+    let next_buses_config = NextBusesConfig::from_env()?;
+    let source = transit_source::transit_source::from_env()?;
+    let (layout, stale, leave_now, ticker) =
+        match get_next_buses(source, &next_buses_config, clock.as_ref()).await {
+            Ok(next_buses) => {
+                let groups = next_buses_config.grouping.map(|grouping| {
+                    let mut groups =
+                        group_arrivals(&next_buses, grouping, next_buses_config.group_size);
+                    groups.truncate(next_buses_config.max_results);
+                    groups
+                });
+                let board = match &groups {
+                    Some(groups) => grouped_board(
+                        groups,
+                        directions,
+                        leave_window,
+                        &line_colors,
+                        clock.as_ref(),
+                    ),
+                    None => departure_board(
+                        &next_buses,
+                        &timezone,
+                        time_display,
+                        directions,
+                        leave_window,
+                        &line_colors,
+                        clock.as_ref(),
+                    ),
+                };
+                // The flags only go by what made it onto the board: every
+                // departure shown for staleness, and the one each row is
+                // styled after for leaving now, since that's what blinks.
+                let (shown, rows): (Vec<&ExpectedBusArrival>, Vec<&ExpectedBusArrival>) =
+                    match &groups {
+                        Some(groups) => (
+                            groups.iter().flat_map(|group| &group.arrivals).collect(),
+                            groups
+                                .iter()
+                                .filter_map(|group| group.arrivals.first())
+                                .collect(),
+                        ),
+                        None => (next_buses.iter().collect(), next_buses.iter().collect()),
+                    };
+                (
+                    board,
+                    shown.iter().any(|arrival| arrival.stale),
+                    leave_window.is_some_and(|window| {
+                        rows.iter()
+                            .any(|arrival| arrival.leave_within(window, clock.as_ref()))
+                    }),
+                    disruption_ticker(&next_buses_config, &next_buses, clock.as_ref()).await,
                 )
-                .unwrap(),
-            ]
-        })
-        .collect::<Vec<_>>()
-        .try_into()
-        .unwrap();
+            }
+            // Problems the API reports are worth showing on the display
+            // rather than just giving up.
+            Err(err) => match err.downcast_ref::<SiriError>() {
+                Some(siri_error) => {
+                    println!("NextBuses error: {}", siri_error);
+                    (message_board(siri_error.summary()), false, false, None)
+                }
+                None => return Err(err),
+            },
+        };
 
-    let layout = vstack![
-        hstack![
-            Ok::<TextWidget, Error>(widget1.clone()),
-            Ok::<TextWidget, Error>(widget2.clone())
-        ],
-        hstack![
-            Ok::<TextWidget, Error>(widget3.clone()),
-            Ok::<TextWidget, Error>(widget4.clone())
-        ],
-        hstack![
-            Ok::<TextWidget, Error>(widget5.clone()),
-            Ok::<TextWidget, Error>(widget6.clone())
-        ]
-    ]
-    .map(|s| s.set_gap(2.0));
-
-    let mut frames: Vec<Vec<u8>> = Vec::new();
-
-    if let Ok(l) = layout {
-        let frame_count = l.frame_count();
-        println!("Frame count: {:?}", frame_count);
-        for frame in 0..frame_count {
-            let mut dt = DrawTarget::new(width, height);
-            l.render(&mut dt, Point::new(2., 2.), frame)?;
-
-            let output = get_rgba(dt);
-            frames.push(output);
+    let mut frames: Vec<(Vec<u8>, u32)> = Vec::new();
+
+    let l = layout
+        .and_then(|board| board_layout(board, ticker, &scroll, page_transition, page_frames))?;
+    let frame_count = l.frame_count();
+    println!("Frame count: {:?}", frame_count);
+    for frame in 0..frame_count {
+        let mut dt = DrawTarget::new(width, height);
+        l.render(&mut dt, Point::new(2., 2.), frame, clock.as_ref())?;
+        if stale {
+            draw_stale_marker(&mut dt, Point::new(width as f32 - 2., 0.), clock.as_ref())?;
         }
+
+        let output = get_rgba(dt);
+        frames.push((output, l.frame_delay(frame)));
     }
 
-    // Step 1: Clone all outputs
-    let cloned_outputs: Vec<_> = frames.into_iter().collect();
+    // Step 1: Merge identical frames and work out when each one starts
+    let (timed_outputs, end) = timed_frames(frames);
 
     // Step 2: Create AnimFrames
-    let frames: Vec<_> = cloned_outputs
+    let frames: Vec<_> = timed_outputs
         .iter()
-        .map(|rgba| AnimFrame::from_rgba(rgba, width as u32, height as u32, 0))
+        .map(|(rgba, timestamp)| {
+            AnimFrame::from_rgba(rgba, width as u32, height as u32, *timestamp)
+        })
         .collect();
 
     // Step 3: Add each frame to the encoder
@@ -423,12 +1314,452 @@ pub async fn render(args: RenderArgs) -> Result<()> {
         .map(|frame| encoder.add_frame(frame))
         .collect();
 
-    let file_contents = encoder.encode().to_vec();
+    let mut file_contents = encoder.encode().to_vec();
+
+    // Step 4: Show the last frame for as long as it should be
+    if let Some((_, last_start)) = timed_outputs.last() {
+        set_last_frame_duration(&mut file_contents, (end - last_start) as u32)?;
+    }
 
     if let Some(filename) = &args.debug {
         std::fs::write(filename, file_contents)?;
     } else {
-        push(&file_contents).await?;
+        // Jump straight to the board when it's time to leave, if asked to.
+        let foreground = leave_now && env_var("LEAVE_NOW_FOREGROUND").is_some_and(|v| v == "true");
+        push(&file_contents, !foreground).await?;
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    pub fn marquee_fits_without_scrolling() {
+        let text = TextWidget::new("60A".into(), "#fff").unwrap();
+        let marquee = MarqueeWidget::new(text, 30.0).unwrap();
+        assert_eq!(marquee.frame_count(), 1);
+        assert_eq!(marquee.offset(0), 0.0);
+    }
+
+    #[rstest]
+    pub fn marquee_scrolls_and_pauses_at_both_ends() {
+        // 46px of text in a 30px slot leaves 16px to scroll.
+        let text = TextWidget::new("Sandyhills".into(), "#fff").unwrap();
+        let marquee = MarqueeWidget {
+            text,
+            width: 30.0,
+            speed: 2.0,
+            pause: 3,
+        };
+        assert_eq!(marquee.overflow(), 16.0);
+        assert_eq!(marquee.frame_count(), 3 + 8 + 3 + 1);
+        assert_eq!(marquee.offset(0), 0.0);
+        assert_eq!(marquee.offset(3), 0.0);
+        assert_eq!(marquee.offset(4), 2.0);
+        assert_eq!(marquee.offset(11), 16.0);
+        assert_eq!(marquee.offset(14), 16.0);
+    }
+
+    #[rstest]
+    pub fn marquees_scroll_as_configured() {
+        let marquee = |scroll: Scroll| {
+            let text = TextWidget::new("Sandyhills".into(), "#fff").unwrap();
+            scroll.marquee(text, 30.0).unwrap().frame_count()
+        };
+        assert_eq!(marquee(Scroll::default()), 10 + 16 + 10 + 1);
+        let scroll = Scroll {
+            speed: Some(2.0),
+            pause: Some(3),
+        };
+        assert_eq!(marquee(scroll), 3 + 8 + 3 + 1);
+    }
+
+    #[rstest]
+    pub fn scrolls_long_directions_between_line_and_time() {
+        let clock = FixedClock(DateTime::parse_from_rfc3339("2024-03-09T15:21:17Z").unwrap());
+        let arrival = ExpectedBusArrival {
+            direction: Some("Easterhouse \u{2013} Shandwick St".to_string()),
+            ..ExpectedBusArrival::example("60A", "2024-03-09T15:26:00Z")
+        };
+        let scroll = Scroll::default();
+        let row = |directions| {
+            arrival_row(
+                &arrival,
+                &chrono_tz::Europe::London,
+                TimeDisplay::Absolute,
+                directions,
+                None,
+                &LineColors::default(),
+                &clock,
+            )
+            .unwrap()
+        };
+        assert_eq!(row(None).frame_count(), 1);
+        // The dash isn't in the small font, so it's left out rather than
+        // failing to draw.
+        assert!(row(Some(&scroll)).frame_count() > 1);
+        let mut dt = DrawTarget::new(WIDTH as i32, 10);
+        row(Some(&scroll))
+            .render(&mut dt, Point::new(0., 0.), 20, &clock)
+            .unwrap();
+        assert!(direction_label("Easterhouse", 4., &Scroll::default())
+            .unwrap()
+            .is_none());
+    }
+
+    #[rstest]
+    pub fn blink_alternates_every_period() {
+        let blink = BlinkWidget {
+            on: Box::new(TextWidget::new("60A".into(), LEAVE_NOW_COLOR).unwrap()),
+            off: Box::new(TextWidget::new("60A".into(), "#fff").unwrap()),
+            period: 3,
+        };
+        assert_eq!(blink.frame_count(), 6);
+        let clock = FixedClock(DateTime::parse_from_rfc3339("2024-03-09T15:21:17Z").unwrap());
+        let render = |frame| {
+            let mut dt = DrawTarget::new(20, 10);
+            blink
+                .render(&mut dt, Point::new(0., 0.), frame, &clock)
+                .unwrap();
+            get_rgba(dt)
+        };
+        assert_eq!(render(0), render(2));
+        assert_ne!(render(2), render(3));
+        assert_eq!(render(3), render(5));
+    }
+
+    #[rstest]
+    pub fn leave_now_blinks_the_time_but_keeps_the_badge() {
+        let clock = FixedClock(DateTime::parse_from_rfc3339("2024-03-09T15:21:17Z").unwrap());
+        let arrival = ExpectedBusArrival {
+            operator: Some("MCGL".to_string()),
+            ..ExpectedBusArrival::example("38", "2024-03-09T15:24:00Z")
+        };
+        let row = departure_row(
+            &arrival,
+            "2".to_string(),
+            None,
+            Some(Duration::minutes(5)),
+            &LineColors::default(),
+            &clock,
+        )
+        .unwrap();
+        let badge_width = BadgeWidget::new(arrival.line.to_string().into(), "#00539f")
+            .unwrap()
+            .measure()
+            .x as usize;
+        let render = |frame| {
+            let mut dt = DrawTarget::new(WIDTH as i32, 10);
+            row.render(&mut dt, Point::new(0., 0.), frame, &clock)
+                .unwrap();
+            let rgba = get_rgba(dt);
+            rgba.chunks(WIDTH as usize * 4)
+                .map(|line| {
+                    (
+                        line[..badge_width * 4].to_vec(),
+                        line[badge_width * 4..].to_vec(),
+                    )
+                })
+                .unzip::<_, _, Vec<_>, Vec<_>>()
+        };
+        let (on_badge, on_time) = render(0);
+        let (off_badge, off_time) = render(BLINK_FRAMES);
+        assert_eq!(on_badge, off_badge);
+        assert_ne!(on_time, off_time);
+    }
+
+    #[rstest]
+    pub fn leaves_off_group_times_that_do_not_fit() {
+        let clock = FixedClock(DateTime::parse_from_rfc3339("2024-03-09T15:21:17Z").unwrap());
+        let arrivals: Vec<_> = [3, 12, 27]
+            .into_iter()
+            .map(|minutes| ExpectedBusArrival {
+                expected_time: clock.now() + Duration::seconds(minutes * 60 + 30),
+                real_time: false,
+                ..ExpectedBusArrival::example("X19A", "2024-03-09T15:21:17Z")
+            })
+            .collect();
+        let room = |line: &str| {
+            let arrival = ExpectedBusArrival::example(line, "2024-03-09T15:21:17Z");
+            let line_colors = LineColors {
+                lines: [(line.to_string(), "#00539f".to_string())].into(),
+                ..Default::default()
+            };
+            WIDTH as f32 - line_label(&arrival, &line_colors).unwrap().measure().x
+        };
+        assert_eq!(
+            fitting_times(&arrivals, room("6"), &clock).unwrap(),
+            "~3 ~12 ~27"
+        );
+        assert_eq!(
+            fitting_times(&arrivals, room("X19A"), &clock).unwrap(),
+            "~3 ~12"
+        );
+        assert_eq!(fitting_times(&arrivals, 0., &clock).unwrap(), "~3");
+    }
+
+    #[rstest]
+    pub fn badge_is_filled_around_its_text_with_rounded_corners() {
+        let clock = FixedClock(DateTime::parse_from_rfc3339("2024-03-09T15:21:17Z").unwrap());
+        let badge = BadgeWidget::new("61".into(), "#00539f").unwrap();
+        let text = TextWidget::new("61".into(), "#fff").unwrap();
+        assert_eq!(badge.text.color, "#fff");
+        assert_eq!(
+            BadgeWidget::new("61".into(), "#fc0").unwrap().text.color,
+            "#000"
+        );
+        assert_eq!(badge.measure().x, text.measure().x + BADGE_PADDING * 2.);
+        let mut dt = DrawTarget::new(20, 10);
+        badge
+            .render(&mut dt, Point::new(0., 0.), 0, &clock)
+            .unwrap();
+        let rgba = get_rgba(dt);
+        let alpha = |x: usize, y: usize| rgba[(y * 20 + x) * 4 + 3];
+        assert_eq!(alpha(0, 0), 0);
+        assert!(alpha(1, 0) > 0);
+        assert!(alpha(0, 1) > 0);
+    }
+
+    fn text_rows(count: usize) -> VStack {
+        VStack {
+            items: (0..count)
+                .map(|i| {
+                    Box::new(TextWidget::new(format!("{}", 60 + i).into(), "#fff").unwrap())
+                        as Box<dyn Widget>
+                })
+                .collect(),
+            gap: 2.0,
+        }
+    }
+
+    #[rstest]
+    pub fn stacks_measure_their_rows_and_gaps() {
+        let row_height = TextWidget::new("60".into(), "#fff").unwrap().measure().y;
+        assert_eq!(text_rows(3).measure().y, row_height * 3. + 2. * 2.);
+        let nested = VStack {
+            items: vec![Box::new(text_rows(2)), Box::new(text_rows(1))],
+            gap: 1.0,
+        };
+        assert_eq!(nested.measure().y, (row_height * 2. + 2.) + 1. + row_height);
+        // Empty stacks still take up a row's worth of space.
+        assert_eq!(text_rows(0).measure().y, 5.0);
+    }
+
+    #[rstest]
+    #[case(3, 1)]
+    #[case(7, 3)]
+    pub fn paginates_rows_in_threes(#[case] rows: usize, #[case] pages: u32) {
+        let board = paginate(text_rows(rows), ROWS_PER_PAGE, PageTransition::Cut, 20);
+        let frames = if pages == 1 { 1 } else { pages * 20 };
+        assert_eq!(board.frame_count(), frames);
+    }
+
+    #[rstest]
+    pub fn ticker_takes_the_bottom_row() {
+        let ticker = "Stop closed for roadworks on Duke St".to_string();
+        // Three rows no longer fit on one page alongside the ticker.
+        let board = board_layout(
+            text_rows(3),
+            Some(ticker),
+            &Scroll::default(),
+            PageTransition::Cut,
+            20,
+        )
+        .unwrap();
+        let plain = board_layout(
+            text_rows(3),
+            None,
+            &Scroll::default(),
+            PageTransition::Cut,
+            20,
+        )
+        .unwrap();
+        assert_eq!(plain.frame_count(), 1);
+        assert!(board.frame_count() >= 2 * 20);
+    }
+
+    #[rstest]
+    pub fn ticker_leaves_out_what_the_font_cannot_draw() {
+        let clock = FixedClock(DateTime::parse_from_rfc3339("2024-03-09T15:21:17Z").unwrap());
+        for ticker in ["Duke St closed \u{1f6a7} use Gallowgate", "\u{1f6a7}"] {
+            let board = board_layout(
+                text_rows(2),
+                Some(ticker.to_string()),
+                &Scroll::default(),
+                PageTransition::Cut,
+                20,
+            )
+            .unwrap();
+            for frame in 0..board.frame_count() {
+                let mut dt = DrawTarget::new(WIDTH as i32, 32);
+                board
+                    .render(&mut dt, Point::new(0., 0.), frame, &clock)
+                    .unwrap();
+            }
+        }
+    }
+
+    #[rstest]
+    pub fn slides_to_the_next_page_at_the_end_of_each() {
+        let paged = PagedWidget::new(vec![Box::new(text_rows(3)), Box::new(text_rows(2))])
+            .set_hold(20)
+            .set_transition(PageTransition::Slide);
+        let height = paged.page_height();
+        assert_eq!(paged.page_at(25), (1, 5));
+        assert_eq!(paged.page_at(40), (0, 0));
+        assert_eq!(paged.slide_offset(20 - SLIDE_FRAMES - 1), 0.0);
+        assert_eq!(paged.slide_offset(19), height);
+        let cut = PagedWidget::new(vec![Box::new(text_rows(3)), Box::new(text_rows(2))])
+            .set_hold(20)
+            .set_transition(PageTransition::Cut);
+        assert_eq!(cut.slide_offset(19), 0.0);
+    }
+
+    #[rstest]
+    pub fn marks_the_current_page() {
+        let clock = FixedClock(DateTime::parse_from_rfc3339("2024-03-09T15:21:17Z").unwrap());
+        let paged = PagedWidget::new(vec![Box::new(text_rows(1)), Box::new(text_rows(1))])
+            .set_hold(10)
+            .set_transition(PageTransition::Cut);
+        let indicator = |frame| {
+            let mut dt = DrawTarget::new(WIDTH as i32, 20);
+            paged
+                .render(&mut dt, Point::new(0., 0.), frame, &clock)
+                .unwrap();
+            let y = (paged.page_height() + 1.) as usize;
+            let rgba = get_rgba(dt);
+            let pixel = |x: usize| rgba[(y * WIDTH as usize + x) * 4];
+            (pixel(WIDTH as usize - 3), pixel(WIDTH as usize - 1))
+        };
+        let (first, second) = indicator(0);
+        assert!(first > second);
+        let (first, second) = indicator(10);
+        assert!(first < second);
+    }
+
+    #[rstest]
+    pub fn timed_frames_merges_repeats_and_accumulates_timestamps() {
+        let frames = vec![
+            (vec![0], 50),
+            (vec![0], 50),
+            (vec![1], 100),
+            (vec![2], 50),
+            (vec![2], 50),
+            (vec![0], 50),
+        ];
+        assert_eq!(
+            timed_frames(frames),
+            (
+                vec![(vec![0], 0), (vec![1], 100), (vec![2], 200), (vec![0], 300)],
+                350
+            )
+        );
+    }
+
+    // The duration of each ANMF chunk in an animated WebP.
+    fn frame_durations(webp: &[u8]) -> Vec<u32> {
+        let mut durations = vec![];
+        let mut offset = 12;
+        while offset + 8 <= webp.len() {
+            let size = u32::from_le_bytes(webp[offset + 4..offset + 8].try_into().unwrap());
+            if &webp[offset..offset + 4] == b"ANMF" {
+                let duration = &webp[offset + 20..offset + 23];
+                durations.push(u32::from_le_bytes([
+                    duration[0],
+                    duration[1],
+                    duration[2],
+                    0,
+                ]));
+            }
+            offset += 8 + size as usize + size as usize % 2;
+        }
+        durations
+    }
+
+    #[rstest]
+    pub fn last_frame_lasts_until_the_end_timestamp() {
+        let mut config = WebPConfig::new().unwrap();
+        config.lossless = 1;
+        let black = vec![0u8; 4 * 4 * 4];
+        let white = vec![255u8; 4 * 4 * 4];
+        let (frames, end) = timed_frames(vec![(black, 100), (white.clone(), 200), (white, 100)]);
+        let mut encoder = AnimEncoder::new(4, 4, &config);
+        for (rgba, timestamp) in &frames {
+            encoder.add_frame(AnimFrame::from_rgba(rgba, 4, 4, *timestamp));
+        }
+        let mut webp = encoder.encode().to_vec();
+        set_last_frame_duration(&mut webp, (end - frames[1].1) as u32).unwrap();
+        assert_eq!(frame_durations(&webp), vec![100, 300]);
+    }
+
+    #[rstest]
+    #[case(0, 1)]
+    #[case(1, 1)]
+    #[case(2, 2)]
+    #[case(5, 5)]
+    pub fn departure_board_has_a_row_per_arrival(#[case] count: usize, #[case] rows: usize) {
+        let clock = FixedClock(DateTime::parse_from_rfc3339("2024-03-09T15:21:17Z").unwrap());
+        let arrivals: Vec<ExpectedBusArrival> = (0..count)
+            .map(|i| {
+                ExpectedBusArrival::example(&format!("{}", 60 + i), "2024-03-09T15:26:18.000Z")
+            })
+            .collect();
+        let board = departure_board(
+            &arrivals,
+            &chrono_tz::Europe::London,
+            TimeDisplay::Absolute,
+            None,
+            None,
+            &LineColors::default(),
+            &clock,
+        )
+        .unwrap();
+        assert_eq!(board.items.len(), rows);
+    }
+
+    #[rstest]
+    pub fn text_is_measured_and_drawn_in_its_own_font() {
+        let clock = FixedClock(DateTime::parse_from_rfc3339("2024-03-09T15:21:17Z").unwrap());
+        let small = TextWidget::new("12".into(), "#fff").unwrap();
+        let big = small.clone().set_font(Font::Fixed10x20);
+        assert_eq!(
+            big.measure(),
+            Point::new(Font::Fixed10x20.glyphs().unwrap().measure("12"), 20.)
+        );
+        assert!(big.measure().x > small.measure().x);
+        let last_lit_row = |text: TextWidget| {
+            let mut dt = DrawTarget::new(30, 24);
+            text.render(&mut dt, Point::new(0., 0.), 0, &clock).unwrap();
+            get_rgba(dt)
+                .chunks(30 * 4)
+                .enumerate()
+                .filter(|(_, row)| row.chunks(4).any(|pixel| pixel[3] > 0))
+                .map(|(y, _)| y)
+                .max()
+        };
+        assert!(last_lit_row(small).unwrap() < 8);
+        assert!(last_lit_row(big).unwrap() >= 8);
+    }
+
+    #[rstest]
+    pub fn struck_text_has_a_line_through_it() {
+        let clock = FixedClock(DateTime::parse_from_rfc3339("2024-03-09T15:21:17Z").unwrap());
+        let render = |text: TextWidget| {
+            let mut dt = DrawTarget::new(20, 10);
+            text.render(&mut dt, Point::new(0., 0.), 0, &clock).unwrap();
+            get_rgba(dt)
+        };
+        let text = TextWidget::new("61".into(), "#f00").unwrap();
+        let plain = render(text.clone());
+        let struck = render(text.set_strike(true));
+        assert_ne!(plain, struck);
+        // Every pixel across the middle row of the text is lit.
+        let middle = 4 * 20 * 4;
+        assert!(struck[middle..middle + 9 * 4]
+            .chunks(4)
+            .all(|pixel| pixel[3] > 0));
+    }
+}
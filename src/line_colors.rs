@@ -0,0 +1,113 @@
+pub mod line_colors {
+    use crate::next_buses::{env_list, ExpectedBusArrival};
+    use anyhow::{anyhow, Result};
+    use palette::Srgb;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    // Operators around Glasgow, by National Operator Code. NextBuses
+    // sometimes gives First Glasgow's older short code instead.
+    const OPERATOR_COLORS: [(&str, &str); 3] =
+        [("FGLA", "#e4007d"), ("FG", "#e4007d"), ("MCGL", "#00539f")];
+
+    /// Badge colours for lines and the operators that run them. A line's
+    /// own colour wins over its operator's.
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct LineColors {
+        pub lines: HashMap<String, String>,
+        pub operators: HashMap<String, String>,
+    }
+
+    impl Default for LineColors {
+        fn default() -> Self {
+            LineColors {
+                lines: HashMap::new(),
+                operators: OPERATOR_COLORS
+                    .iter()
+                    .map(|(operator, color)| (operator.to_string(), color.to_string()))
+                    .collect(),
+            }
+        }
+    }
+
+    impl LineColors {
+        /// Adds to the built-in colours from `LINE_COLORS` and
+        /// `OPERATOR_COLORS`, each a list like `60A=#0a0,61=#a0a`.
+        pub fn from_env() -> Result<LineColors> {
+            let mut colors = LineColors::default();
+            colors.lines.extend(parse_colors(&env_list("LINE_COLORS"))?);
+            colors
+                .operators
+                .extend(parse_colors(&env_list("OPERATOR_COLORS"))?);
+            Ok(colors)
+        }
+
+        pub fn color_for(&self, arrival: &ExpectedBusArrival) -> Option<&str> {
+            // Keys are upper case, as operator codes already are.
+            let line = self
+                .lines
+                .get(&arrival.line.to_uppercase())
+                .map(String::as_str);
+            let operator = || {
+                self.operators
+                    .get(arrival.operator.as_deref()?)
+                    .map(String::as_str)
+            };
+            line.or_else(operator)
+        }
+    }
+
+    fn parse_colors(items: &[String]) -> Result<HashMap<String, String>> {
+        items
+            .iter()
+            .map(|item| {
+                let (key, color) = item
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Expected <line>=<colour>, got {}", item))?;
+                let color = color.trim();
+                Srgb::<u8>::from_str(color)
+                    .map_err(|err| anyhow!("Invalid colour {}: {}", color, err))?;
+                Ok((key.trim().to_uppercase(), color.to_string()))
+            })
+            .collect()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use rstest::*;
+
+        fn arrival(line: &str, operator: Option<&str>) -> ExpectedBusArrival {
+            ExpectedBusArrival {
+                operator: operator.map(String::from),
+                ..ExpectedBusArrival::example(line, "2024-03-09T15:26:18.000Z")
+            }
+        }
+
+        #[rstest]
+        #[case("60A", Some("FGLA"), Some("#0a0"))]
+        #[case("60a", None, Some("#0a0"))]
+        #[case("61", Some("FGLA"), Some("#e4007d"))]
+        #[case("38", Some("MCGL"), Some("#00539f"))]
+        #[case("17", Some("WCMO"), None)]
+        #[case("17", None, None)]
+        pub fn picks_line_then_operator_colour(
+            #[case] line: &str,
+            #[case] operator: Option<&str>,
+            #[case] color: Option<&str>,
+        ) {
+            let mut colors = LineColors::default();
+            colors
+                .lines
+                .extend(parse_colors(&["60A=#0a0".to_string()]).unwrap());
+            assert_eq!(colors.color_for(&arrival(line, operator)), color);
+        }
+
+        #[rstest]
+        #[case("60A")]
+        #[case("60A=green")]
+        pub fn rejects_bad_colour_mappings(#[case] item: &str) {
+            assert!(parse_colors(&[item.to_string()]).is_err());
+        }
+    }
+}
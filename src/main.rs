@@ -1,3 +1,4 @@
+use chrono::{DateTime, FixedOffset};
 use clap::Parser;
 use dotenvy::dotenv;
 use std::time::Duration;
@@ -12,12 +13,15 @@ struct Args {
     debug: Option<String>,
     #[arg(short, long)]
     retry: Option<u64>,
+    /// Render the display as of this RFC 3339 timestamp instead of now
+    #[arg(long)]
+    at: Option<DateTime<FixedOffset>>,
 }
 
 impl From<Args> for RenderArgs {
     fn from(value: Args) -> Self {
-        let Args { debug, retry } = value;
-        Self::new(debug, retry)
+        let Args { debug, retry, at } = value;
+        Self::new(debug, retry, at)
     }
 }
 
@@ -25,7 +29,7 @@ impl From<Args> for RenderArgs {
 async fn main() -> anyhow::Result<()> {
     let _ = dotenv();
     let args = Args::parse();
-    let duration = args.retry.map(|retry_time| Duration::from_secs(retry_time));
+    let duration = args.retry.map(Duration::from_secs);
 
     loop {
         render(args.clone().into()).await?;
@@ -1,50 +1,647 @@
-use anyhow::{bail, Result};
-use chrono::{DateTime, FixedOffset, Local};
+use crate::clock::clock::{Clock, FixedClock};
+use crate::siri_request::siri_request::StopMonitoringRequest;
+use crate::transit_source::transit_source::TransitSource;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, FixedOffset};
 use quick_xml::events::{BytesStart, Event};
 use quick_xml::Reader;
 use reqwest::header::USER_AGENT;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
 
+const NEXT_BUSES_ENDPOINT: &str = "http://nextbus.mxdata.co.uk/nextbuses/1.0/1";
 
-pub async fn get_next_buses<'a>() -> Result<Vec<ExpectedBusArrival>> {
-    let api_user = dotenvy::var("NEXT_BUSES_API_USER")?;
-    let api_pass = dotenvy::var("NEXT_BUSES_API_PASS")?;
-    let bus_stop_code = dotenvy::var("BUS_STOP_NAPTAN_CODE")?;
-    let now: DateTime<FixedOffset> = Local::now().into();
-    let now_str = now.to_rfc3339();
-    let payload = format!(
-        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
-<Siri version="1.0" xmlns="http://www.siri.org.uk/">
-<ServiceRequest>
-<RequestTimestamp>{}</RequestTimestamp>
-<RequestorRef>{}</RequestorRef>
-<StopMonitoringRequest version="1.0">
-<RequestTimestamp>{}</RequestTimestamp>
-<MessageIdentifier>{}</MessageIdentifier>
-<MonitoringRef>{}</MonitoringRef>
-</StopMonitoringRequest>
-</ServiceRequest>
-</Siri>"#,
-        now_str, api_user, now_str, "garbage", bus_stop_code
-    );
+// The most departures shown, paged through a few at a time.
+const DEFAULT_MAX_RESULTS: usize = 9;
+
+// The most departures shown on each line's row when grouping.
+const DEFAULT_GROUP_SIZE: usize = 3;
+
+/// A stop to query, with the minutes it takes to walk there. Buses
+/// leaving sooner than that can't be caught, so they are skipped.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BusStop {
+    pub naptan_code: String,
+    pub minutes_away: u32,
+}
+
+impl BusStop {
+    /// Parses a comma separated list of stops. Each stop can give its
+    /// own walking time as `code:minutes`, and otherwise uses the default.
+    pub fn parse_list(stops: &str, default_minutes_away: u32) -> Result<Vec<BusStop>> {
+        stops
+            .split(',')
+            .map(str::trim)
+            .filter(|stop| !stop.is_empty())
+            .map(|stop| BusStop::parse(stop, default_minutes_away))
+            .collect()
+    }
+
+    fn parse(stop: &str, default_minutes_away: u32) -> Result<BusStop> {
+        let (naptan_code, minutes_away) = match stop.split_once(':') {
+            Some((naptan_code, minutes_away)) => (naptan_code, minutes_away.parse::<u32>()?),
+            None => (stop, default_minutes_away),
+        };
+        Ok(BusStop {
+            naptan_code: naptan_code.to_string(),
+            minutes_away,
+        })
+    }
+}
+
+/// Everything needed to look up departures from the NextBuses API.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NextBusesConfig {
+    pub endpoint: String,
+    // Only the NextBuses source and SIRI-SX need a login.
+    pub api_user: Option<String>,
+    pub api_pass: Option<String>,
+    pub stops: Vec<BusStop>,
+    pub filter: ArrivalFilter,
+    pub max_results: usize,
+    // Optional limits passed on to the API, so that it can do some of
+    // the filtering itself.
+    pub preview_interval: Option<u32>,
+    pub maximum_stop_visits: Option<usize>,
+    // Fail the whole parse on a bad visit rather than skipping it.
+    pub strict: bool,
+    // Show a row per line rather than per bus, with its next few times.
+    pub grouping: Option<Grouping>,
+    pub group_size: usize,
+}
+
+impl NextBusesConfig {
+    pub fn new(stops: Vec<BusStop>) -> NextBusesConfig {
+        NextBusesConfig {
+            endpoint: NEXT_BUSES_ENDPOINT.to_string(),
+            api_user: None,
+            api_pass: None,
+            stops,
+            filter: ArrivalFilter::default(),
+            max_results: DEFAULT_MAX_RESULTS,
+            preview_interval: None,
+            maximum_stop_visits: None,
+            strict: false,
+            grouping: None,
+            group_size: DEFAULT_GROUP_SIZE,
+        }
+    }
+
+    pub fn from_env() -> Result<NextBusesConfig> {
+        let default_minutes_away = dotenvy::var("MINUTES_AWAY")?.parse::<u32>()?;
+        let stops =
+            BusStop::parse_list(&dotenvy::var("BUS_STOP_NAPTAN_CODE")?, default_minutes_away)?;
+        Ok(NextBusesConfig {
+            endpoint: env_var("NEXT_BUSES_ENDPOINT")
+                .unwrap_or_else(|| NEXT_BUSES_ENDPOINT.to_string()),
+            api_user: env_var("NEXT_BUSES_API_USER"),
+            api_pass: env_var("NEXT_BUSES_API_PASS"),
+            filter: ArrivalFilter::from_env(),
+            max_results: env_number("MAX_RESULTS")?.unwrap_or(DEFAULT_MAX_RESULTS),
+            preview_interval: env_number("PREVIEW_INTERVAL")?,
+            maximum_stop_visits: env_number("MAXIMUM_STOP_VISITS")?,
+            grouping: env_var("GROUP_BY")
+                .map(|grouping| grouping.parse())
+                .transpose()?,
+            group_size: env_number("GROUP_SIZE")?.unwrap_or(DEFAULT_GROUP_SIZE),
+            ..NextBusesConfig::new(stops)
+        })
+    }
+
+    pub fn set_credentials(mut self, api_user: String, api_pass: String) -> Self {
+        self.api_user = Some(api_user);
+        self.api_pass = Some(api_pass);
+        self
+    }
+
+    /// The NextBuses login, which is also good for SIRI-SX.
+    pub fn credentials(&self) -> Result<(&str, &str)> {
+        match (&self.api_user, &self.api_pass) {
+            (Some(user), Some(pass)) => Ok((user, pass)),
+            _ => Err(anyhow!(
+                "NEXT_BUSES_API_USER and NEXT_BUSES_API_PASS are needed for NextBuses"
+            )),
+        }
+    }
+
+    pub fn stop_monitoring_request(
+        &self,
+        stop: &BusStop,
+        clock: &dyn Clock,
+    ) -> StopMonitoringRequest {
+        StopMonitoringRequest::new(
+            self.api_user.clone().unwrap_or_default(),
+            stop.naptan_code.clone(),
+            clock.now(),
+        )
+        .set_preview_interval(self.preview_interval)
+        .set_maximum_stop_visits(self.maximum_stop_visits)
+        .set_line_ref(self.filter.single_line())
+    }
+
+    /// How many buses to keep from each stop. When grouping, `max_results`
+    /// counts lines rather than buses, so keep them all until grouped.
+    pub fn visit_limit(&self) -> usize {
+        match self.grouping {
+            Some(_) => usize::MAX,
+            None => self.max_results,
+        }
+    }
+}
+
+// Reads an optional setting. CI passes unset secrets through as empty
+// strings, so those count as missing too.
+pub(crate) fn env_var(key: &str) -> Option<String> {
+    dotenvy::var(key)
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+}
+
+pub(crate) fn env_number<T>(key: &str) -> Result<Option<T>>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    Ok(env_var(key).map(|value| value.trim().parse()).transpose()?)
+}
+
+/// The soonest departures from all the configured stops. A stop that
+/// can't be looked up is logged and left out, so that it doesn't cost
+/// the board the others; only when every stop fails is that an error.
+pub async fn get_next_buses(
+    source: Arc<dyn TransitSource>,
+    config: &NextBusesConfig,
+    clock: &dyn Clock,
+) -> Result<Vec<ExpectedBusArrival>> {
+    // Every stop is looked up as of the same moment.
+    let clock = FixedClock(clock.now());
+
+    let mut requests = tokio::task::JoinSet::new();
+    for stop in config.stops.clone() {
+        let source = source.clone();
+        let config = config.clone();
+        requests.spawn(async move {
+            let arrivals = source.stop_arrivals(&config, &stop, &clock).await;
+            (stop, arrivals)
+        });
+    }
+
+    let mut arrivals = Vec::new();
+    let mut error: Option<anyhow::Error> = None;
+    let mut succeeded = false;
+    while let Some(request) = requests.join_next().await {
+        match request? {
+            (_, Ok(stop_arrivals)) => {
+                arrivals.extend(stop_arrivals);
+                succeeded = true;
+            }
+            (stop, Err(err)) => {
+                println!("Departures for {} failed: {}", stop.naptan_code, err);
+                // Keep one the API reported, if any, since the board shows those.
+                let reported = |err: &anyhow::Error| err.downcast_ref::<SiriError>().is_some();
+                if !error.as_ref().is_some_and(reported) {
+                    error = Some(err);
+                }
+            }
+        }
+    }
+    match error {
+        Some(err) if !succeeded => Err(err),
+        _ => Ok(merge_arrivals(arrivals, config.visit_limit())),
+    }
+}
+
+/// The Traveline NextBuses SIRI API.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NextBusesSource;
+
+#[async_trait]
+impl TransitSource for NextBusesSource {
+    fn name(&self) -> &str {
+        "nextbuses"
+    }
+
+    async fn stop_visits(
+        &self,
+        config: &NextBusesConfig,
+        stop: &BusStop,
+        clock: &dyn Clock,
+    ) -> Result<Vec<ExpectedBusArrival>> {
+        get_stop_visits(config, stop, clock).await
+    }
+}
+
+async fn get_stop_visits(
+    config: &NextBusesConfig,
+    stop: &BusStop,
+    clock: &dyn Clock,
+) -> Result<Vec<ExpectedBusArrival>> {
+    let (api_user, api_pass) = config.credentials()?;
+    let payload = config.stop_monitoring_request(stop, clock).to_xml()?;
     let api_response = reqwest::Client::new()
-        .post(format!(
-            "http://{}:{}@nextbus.mxdata.co.uk/nextbuses/1.0/1",
-            api_user, api_pass
-        ))
+        .post(&config.endpoint)
+        .basic_auth(api_user, Some(api_pass))
         .body(payload)
         .header(USER_AGENT, "tidbyt")
         .send()
-        .await?
-        .text()
         .await?;
-    let lookup = BusArrivalsLookup::from_xml(api_response.as_str())?;
+    // A bad login is rejected before it gets as far as a SIRI response.
+    if let StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN = api_response.status() {
+        return Err(SiriError::new(SiriErrorKind::AuthFailed).into());
+    }
+    let api_response = api_response.text().await?;
+    let lookup = BusArrivalsLookup::parse(api_response.as_str(), config)?;
+    for warning in lookup.warnings() {
+        println!("Stop {}: {}", stop.naptan_code, warning);
+    }
     Ok(lookup.arrivals().to_owned())
 }
 
+/// Combines the arrivals from every stop into the soonest departures.
+pub fn merge_arrivals(
+    mut arrivals: Vec<ExpectedBusArrival>,
+    max_results: usize,
+) -> Vec<ExpectedBusArrival> {
+    arrivals.sort_by_key(|arrival| arrival.expected_time);
+    take_running(arrivals, max_results)
+}
+
+/// Keeps the first `limit` buses that are still running. Cancelled ones
+/// in among them are kept too, but don't count, so they never push a bus
+/// that can be caught off the board.
+fn take_running(
+    arrivals: impl IntoIterator<Item = ExpectedBusArrival>,
+    limit: usize,
+) -> Vec<ExpectedBusArrival> {
+    let mut remaining = limit;
+    arrivals
+        .into_iter()
+        .take_while(|arrival| {
+            if remaining == 0 {
+                return false;
+            }
+            if !arrival.is_cancelled() {
+                remaining -= 1;
+            }
+            true
+        })
+        .collect()
+}
+
+/// Picks the departures from one stop worth showing: those that pass
+/// the filter and can still be caught, tagged with the stop they leave from.
+pub fn select_arrivals(
+    arrivals: Vec<ExpectedBusArrival>,
+    config: &NextBusesConfig,
+    stop: &BusStop,
+    clock: &dyn Clock,
+) -> Vec<ExpectedBusArrival> {
+    let catchable = arrivals
+        .into_iter()
+        .filter(|arrival| config.filter.matches(arrival))
+        // Buses that have already left can't be caught either, even if
+        // their expected time hasn't passed yet.
+        .filter(|arrival| arrival.departure_status != Some(DepartureStatus::Departed))
+        .filter(|arrival| {
+            arrival
+                .minutes_from_now(clock)
+                .is_ok_and(|minutes| minutes >= stop.minutes_away)
+        })
+        .map(|arrival| ExpectedBusArrival {
+            stop: arrival.stop.or_else(|| Some(stop.naptan_code.clone())),
+            walking_minutes: stop.minutes_away,
+            ..arrival
+        });
+    take_running(catchable, config.visit_limit())
+}
+
+// Minutes behind the timetable at which a bus counts as late, and very late.
+const LATE_MINUTES: i64 = 3;
+const VERY_LATE_MINUTES: i64 = 10;
+
+/// What to group departures by when showing a row per line.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Grouping {
+    Line,
+    LineAndDirection,
+}
+
+/// Parses "line" or "direction".
+impl FromStr for Grouping {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "line" => Ok(Grouping::Line),
+            "direction" => Ok(Grouping::LineAndDirection),
+            _ => Err(anyhow!("Unknown grouping {}", s)),
+        }
+    }
+}
+
+/// The next few departures on one line, or one line in one direction.
 #[derive(Clone, Debug, PartialEq)]
+pub struct ArrivalGroup {
+    pub line: String,
+    pub direction: Option<String>,
+    pub arrivals: Vec<ExpectedBusArrival>,
+}
+
+/// Groups arrivals that are already in time order, keeping the first
+/// `group_size` of each. Groups come in order of their next departure.
+/// Cancelled buses are left out, since there's nothing to catch.
+pub fn group_arrivals(
+    arrivals: &[ExpectedBusArrival],
+    grouping: Grouping,
+    group_size: usize,
+) -> Vec<ArrivalGroup> {
+    let mut groups: Vec<ArrivalGroup> = Vec::new();
+    for arrival in arrivals.iter().filter(|arrival| !arrival.is_cancelled()) {
+        let direction = match grouping {
+            Grouping::Line => None,
+            Grouping::LineAndDirection => arrival.direction.clone(),
+        };
+        let existing = groups
+            .iter_mut()
+            .find(|group| group.line == arrival.line && group.direction == direction);
+        match existing {
+            Some(group) if group.arrivals.len() < group_size => {
+                group.arrivals.push(arrival.clone())
+            }
+            Some(_) => (),
+            None => groups.push(ArrivalGroup {
+                line: arrival.line.clone(),
+                direction,
+                arrivals: vec![arrival.clone()],
+            }),
+        }
+    }
+    groups
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Punctuality {
+    OnTime,
+    Late,
+    VeryLate,
+}
+
+/// The SIRI `DepartureStatus` of a call.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DepartureStatus {
+    OnTime,
+    Early,
+    Delayed,
+    Cancelled,
+    Arrived,
+    Departed,
+    Missed,
+    NoReport,
+    NotExpected,
+}
+
+impl FromStr for DepartureStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "onTime" => Ok(DepartureStatus::OnTime),
+            "early" => Ok(DepartureStatus::Early),
+            "delayed" => Ok(DepartureStatus::Delayed),
+            "cancelled" => Ok(DepartureStatus::Cancelled),
+            "arrived" => Ok(DepartureStatus::Arrived),
+            "departed" => Ok(DepartureStatus::Departed),
+            "missed" => Ok(DepartureStatus::Missed),
+            "noReport" => Ok(DepartureStatus::NoReport),
+            "notExpected" => Ok(DepartureStatus::NotExpected),
+            _ => Err(anyhow!("Unknown departure status {}", s)),
+        }
+    }
+}
+
+/// The SIRI `VehicleMode` of a journey.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VehicleMode {
+    Air,
+    Bus,
+    Coach,
+    Ferry,
+    Metro,
+    Rail,
+    Tram,
+    Underground,
+}
+
+impl FromStr for VehicleMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "air" => Ok(VehicleMode::Air),
+            "bus" => Ok(VehicleMode::Bus),
+            "coach" => Ok(VehicleMode::Coach),
+            "ferry" => Ok(VehicleMode::Ferry),
+            "metro" => Ok(VehicleMode::Metro),
+            "rail" => Ok(VehicleMode::Rail),
+            "tram" => Ok(VehicleMode::Tram),
+            "underground" => Ok(VehicleMode::Underground),
+            _ => Err(anyhow!("Unknown vehicle mode {}", s)),
+        }
+    }
+}
+
+/// Turns an `OperatorRef` into a bare National Operator Code. Some
+/// feeds prefix it, e.g. `_noc_FGLA`, and others don't.
+pub fn normalise_operator(operator_ref: &str) -> String {
+    let operator_ref = operator_ref.trim();
+    let code = match operator_ref.get(..5) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("_noc_") => &operator_ref[5..],
+        _ => operator_ref,
+    };
+    code.to_ascii_uppercase()
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ExpectedBusArrival {
     pub line: String,
+    pub direction: Option<String>,
+    // The NaPTAN code of the stop the bus leaves from.
+    pub stop: Option<String>,
+    // That stop's ATCO code, which SIRI-SX uses, when the feed gives it.
+    pub atco_code: Option<String>,
+    // Minutes needed to walk to that stop.
+    pub walking_minutes: u32,
     pub expected_time: DateTime<FixedOffset>,
+    pub aimed_time: Option<DateTime<FixedOffset>>,
+    // False when the feed only had a timetabled time for this visit.
+    pub real_time: bool,
+    pub departure_status: Option<DepartureStatus>,
+    // The National Operator Code of the company running the bus.
+    pub operator: Option<String>,
+    pub vehicle_mode: Option<VehicleMode>,
+    // When the feed last heard about this visit.
+    pub recorded_at: Option<DateTime<FixedOffset>>,
+    // True when this came from the cache because the live fetch failed.
+    #[serde(skip)]
+    pub stale: bool,
+}
+
+/// Why a `MonitoredStopVisit` couldn't be read as an arrival.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VisitError {
+    Missing(&'static str),
+    Invalid(&'static str),
+}
+
+impl fmt::Display for VisitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VisitError::Missing(field) => write!(f, "missing {}", field),
+            VisitError::Invalid(field) => write!(f, "invalid {}", field),
+        }
+    }
+}
+
+impl std::error::Error for VisitError {}
+
+/// A visit that was skipped, by its position in the response.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseWarning {
+    pub index: usize,
+    pub error: VisitError,
+}
+
+impl fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "skipped MonitoredStopVisit {}: {}",
+            self.index, self.error
+        )
+    }
+}
+
+/// The kinds of `ErrorCondition` the API reports, grouped by what
+/// someone looking at the board would need to do about them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SiriErrorKind {
+    AuthFailed,
+    UnknownStop,
+    OverQuota,
+    Other,
+}
+
+impl SiriErrorKind {
+    fn from_condition(name: &[u8]) -> Option<Self> {
+        match name {
+            b"AccessNotAllowedError" => Some(SiriErrorKind::AuthFailed),
+            b"UnknownEndpointError" | b"InvalidDataReferencesError" | b"NoInfoForTopicError" => {
+                Some(SiriErrorKind::UnknownStop)
+            }
+            b"AllowedResourceUsageExceededError" => Some(SiriErrorKind::OverQuota),
+            b"OtherError" | b"CapabilityNotSupportedError" | b"ServiceNotAvailableError" => {
+                Some(SiriErrorKind::Other)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A delivery with `Status` false, along with the provider's own
+/// explanation when it gives one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SiriError {
+    pub kind: SiriErrorKind,
+    pub description: Option<String>,
+}
+
+impl SiriError {
+    pub fn new(kind: SiriErrorKind) -> Self {
+        SiriError {
+            kind,
+            description: None,
+        }
+    }
+
+    /// Reads an `ErrorCondition`, e.g.
+    /// `<ErrorCondition><AccessNotAllowedError/><Description>..</Description></ErrorCondition>`.
+    pub fn new_from_element(reader: &mut Reader<&[u8]>) -> Result<Self, anyhow::Error> {
+        let mut buf = Vec::new();
+        let mut kind: Option<SiriErrorKind> = None;
+        let mut error_text: Option<String> = None;
+        let mut description: Option<String> = None;
+
+        loop {
+            let event = reader.read_event_into(&mut buf)?;
+
+            match event {
+                Event::Start(el) => match el.name().as_ref() {
+                    b"ErrorText" => error_text = Some(reader.read_text(el.name())?.into()),
+                    b"Description" => description = Some(reader.read_text(el.name())?.into()),
+                    name => kind = kind.or(SiriErrorKind::from_condition(name)),
+                },
+                Event::Empty(el) => {
+                    kind = kind.or(SiriErrorKind::from_condition(el.name().as_ref()))
+                }
+                Event::End(el) if el.name().as_ref() == b"ErrorCondition" => break,
+                Event::Eof => break,
+                _ => (),
+            }
+        }
+
+        Ok(SiriError {
+            kind: kind.unwrap_or(SiriErrorKind::Other),
+            description: description.or(error_text),
+        })
+    }
+
+    /// Short enough to fit on the display.
+    pub fn summary(&self) -> &'static str {
+        match self.kind {
+            SiriErrorKind::AuthFailed => "Auth failed",
+            SiriErrorKind::UnknownStop => "Unknown stop",
+            SiriErrorKind::OverQuota => "Over quota",
+            SiriErrorKind::Other => "API error",
+        }
+    }
+}
+
+impl fmt::Display for SiriError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.description {
+            Some(description) => write!(f, "{}: {}", self.summary(), description),
+            None => write!(f, "{}", self.summary()),
+        }
+    }
+}
+
+impl std::error::Error for SiriError {}
+
+#[cfg(test)]
+impl ExpectedBusArrival {
+    /// A live bus on `line` expected at `expected_time`, with nothing else
+    /// known about it, for tests to fill in with struct update syntax.
+    pub(crate) fn example(line: &str, expected_time: &str) -> ExpectedBusArrival {
+        ExpectedBusArrival {
+            line: line.to_string(),
+            direction: None,
+            stop: None,
+            atco_code: None,
+            walking_minutes: 0,
+            expected_time: DateTime::parse_from_rfc3339(expected_time).unwrap(),
+            aimed_time: None,
+            real_time: true,
+            departure_status: None,
+            operator: None,
+            vehicle_mode: None,
+            recorded_at: None,
+            stale: false,
+        }
+    }
 }
 
 impl ExpectedBusArrival {
@@ -55,8 +652,16 @@ impl ExpectedBusArrival {
     ) -> Result<Self, anyhow::Error> {
         let mut buf = Vec::new();
         let mut line: Option<String> = None;
+        let mut direction: Option<String> = None;
+        let mut stop: Option<String> = None;
+        let mut atco_code: Option<String> = None;
         let mut expected_time: Option<DateTime<FixedOffset>> = None;
         let mut aimed_time: Option<DateTime<FixedOffset>> = None;
+        let mut departure_status: Option<DepartureStatus> = None;
+        let mut operator: Option<String> = None;
+        let mut vehicle_mode: Option<VehicleMode> = None;
+        let mut recorded_at: Option<DateTime<FixedOffset>> = None;
+        let mut invalid: Option<&'static str> = None;
 
         loop {
             let event = reader.read_event_into(&mut buf)?;
@@ -66,15 +671,44 @@ impl ExpectedBusArrival {
                     b"PublishedLineName" => {
                         line = Some(reader.read_text(el.name())?.into());
                     }
+                    b"MonitoringRef" => {
+                        stop = Some(reader.read_text(el.name())?.into());
+                    }
+                    b"StopPointRef" => {
+                        atco_code = Some(reader.read_text(el.name())?.into());
+                    }
+                    b"DirectionName" => {
+                        direction = Some(reader.read_text(el.name())?.into());
+                    }
+                    b"OperatorRef" => {
+                        operator = Some(normalise_operator(&reader.read_text(el.name())?));
+                    }
+                    // Statuses and modes this doesn't know about are as good as none.
+                    b"DepartureStatus" => {
+                        departure_status = reader.read_text(el.name())?.parse().ok();
+                    }
+                    b"VehicleMode" => {
+                        vehicle_mode = reader.read_text(el.name())?.parse().ok();
+                    }
+                    // Only informational, so a bad one doesn't spoil the visit.
+                    b"RecordedAtTime" => {
+                        recorded_at =
+                            DateTime::parse_from_rfc3339(reader.read_text(el.name())?.as_ref())
+                                .ok();
+                    }
+                    // Keep reading to the end of the visit even if a time is
+                    // bad, so that the next visit can still be parsed.
                     b"ExpectedDepartureTime" => {
-                        expected_time = Some(DateTime::parse_from_rfc3339(
-                            reader.read_text(el.name())?.as_ref(),
-                        )?)
+                        match DateTime::parse_from_rfc3339(reader.read_text(el.name())?.as_ref()) {
+                            Ok(time) => expected_time = Some(time),
+                            Err(_) => invalid = Some("ExpectedDepartureTime"),
+                        }
                     }
                     b"AimedDepartureTime" => {
-                        aimed_time = Some(DateTime::parse_from_rfc3339(
-                            reader.read_text(el.name())?.as_ref(),
-                        )?)
+                        match DateTime::parse_from_rfc3339(reader.read_text(el.name())?.as_ref()) {
+                            Ok(time) => aimed_time = Some(time),
+                            Err(_) => invalid = Some("AimedDepartureTime"),
+                        }
                     }
                     _ => (),
                 },
@@ -84,34 +718,157 @@ impl ExpectedBusArrival {
             }
         }
 
+        if let Some(field) = invalid {
+            return Err(VisitError::Invalid(field).into());
+        }
+
+        let real_time = expected_time.is_some();
         let expected_time = expected_time.or(aimed_time);
 
-        let (line, expected_time) = match (line, expected_time) {
-            (Some(line), Some(expected_time)) => (line, expected_time),
-            _ => bail!("did not parse"),
-        };
+        let line = line.ok_or(VisitError::Missing("PublishedLineName"))?;
+        let expected_time = expected_time.ok_or(VisitError::Missing("AimedDepartureTime"))?;
 
         Ok(ExpectedBusArrival {
             line,
+            direction,
+            stop,
+            atco_code,
+            walking_minutes: 0,
             expected_time,
+            aimed_time,
+            real_time,
+            departure_status,
+            operator,
+            vehicle_mode,
+            recorded_at,
+            stale: false,
         })
     }
 
-    pub fn minutes_from_now(&self) -> Result<u32, anyhow::Error> {
-        let local: DateTime<Local> = Local::now();
-        let now: DateTime<FixedOffset> = local.into();
-        Ok((self.expected_time - now).num_minutes().try_into()?)
+    /// How far behind the timetable the bus is running. Early buses
+    /// report a negative delay.
+    pub fn delay(&self) -> Duration {
+        self.aimed_time
+            .map(|aimed_time| self.expected_time - aimed_time)
+            .unwrap_or_else(Duration::zero)
+    }
+
+    pub fn punctuality(&self) -> Punctuality {
+        match self.delay().num_minutes() {
+            m if m >= VERY_LATE_MINUTES => Punctuality::VeryLate,
+            m if m >= LATE_MINUTES => Punctuality::Late,
+            _ => Punctuality::OnTime,
+        }
+    }
+
+    /// When to set off to catch this bus.
+    pub fn leave_time(&self) -> DateTime<FixedOffset> {
+        self.expected_time - Duration::minutes(self.walking_minutes.into())
+    }
+
+    /// Whether it's time to leave for this bus within the next `window`.
+    pub fn leave_within(&self, window: Duration, clock: &dyn Clock) -> bool {
+        let until_leave = self.leave_time() - clock.now();
+        !self.is_cancelled() && until_leave >= Duration::zero() && until_leave <= window
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.departure_status == Some(DepartureStatus::Cancelled)
+    }
+
+    pub fn minutes_from_now(&self, clock: &dyn Clock) -> Result<u32, anyhow::Error> {
+        Ok((self.expected_time - clock.now())
+            .num_minutes()
+            .try_into()?)
+    }
+}
+
+/// Include and exclude lists for the lines and directions shown on the
+/// board. An empty include list lets everything through.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ArrivalFilter {
+    pub include_lines: Vec<String>,
+    pub exclude_lines: Vec<String>,
+    pub include_directions: Vec<String>,
+    pub exclude_directions: Vec<String>,
+}
+
+impl ArrivalFilter {
+    pub fn from_env() -> ArrivalFilter {
+        ArrivalFilter {
+            include_lines: env_list("INCLUDE_LINES"),
+            exclude_lines: env_list("EXCLUDE_LINES"),
+            include_directions: env_list("INCLUDE_DIRECTIONS"),
+            exclude_directions: env_list("EXCLUDE_DIRECTIONS"),
+        }
+    }
+
+    pub fn matches(&self, arrival: &ExpectedBusArrival) -> bool {
+        // Lines are matched exactly, but directions only need to contain
+        // the filter because operators spell the same destination differently.
+        let line_matches = |line: &String| line.eq_ignore_ascii_case(&arrival.line);
+        let direction = arrival.direction.as_deref().unwrap_or("").to_lowercase();
+        let direction_matches = |filter: &String| direction.contains(&filter.to_lowercase());
+
+        (self.include_lines.is_empty() || self.include_lines.iter().any(line_matches))
+            && !self.exclude_lines.iter().any(line_matches)
+            && (self.include_directions.is_empty()
+                || self.include_directions.iter().any(direction_matches))
+            && !self.exclude_directions.iter().any(direction_matches)
+    }
+
+    /// SIRI only lets a request name one `LineRef`, so the API can only
+    /// do the line filtering for us when a single line is wanted.
+    pub fn single_line(&self) -> Option<String> {
+        match self.include_lines.as_slice() {
+            [line] => Some(line.clone()),
+            _ => None,
+        }
     }
 }
 
+// Reads a comma separated list, treating a missing variable as empty.
+pub(crate) fn env_list(key: &str) -> Vec<String> {
+    env_var(key)
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 #[derive(Debug, PartialEq)]
 pub struct BusArrivalsLookup {
     arrivals: Vec<ExpectedBusArrival>,
+    warnings: Vec<ParseWarning>,
 }
 
 impl BusArrivalsLookup {
-    pub fn from_xml(xml: &str) -> Result<BusArrivalsLookup, anyhow::Error> {
+    /// Reads the departures worth showing out of a SIRI-SM response.
+    pub fn from_xml(
+        xml: &str,
+        config: &NextBusesConfig,
+        stop: &BusStop,
+        clock: &dyn Clock,
+    ) -> Result<BusArrivalsLookup, anyhow::Error> {
+        let lookup = BusArrivalsLookup::parse(xml, config)?;
+        Ok(BusArrivalsLookup {
+            arrivals: select_arrivals(lookup.arrivals, config, stop, clock),
+            ..lookup
+        })
+    }
+
+    /// Reads every visit in a SIRI-SM response, without filtering them.
+    pub fn parse(xml: &str, config: &NextBusesConfig) -> Result<BusArrivalsLookup, anyhow::Error> {
         let mut arrivals: Vec<ExpectedBusArrival> = vec![];
+        let mut warnings: Vec<ParseWarning> = vec![];
+        let mut index = 0;
+        let mut status = true;
+        let mut error: Option<SiriError> = None;
 
         let mut reader = Reader::from_str(xml);
         reader.trim_text(true);
@@ -122,34 +879,57 @@ impl BusArrivalsLookup {
             let event = reader.read_event_into(&mut buf)?;
 
             match event {
-                Event::Start(element) => {
-                    if element.name().as_ref() == b"MonitoredStopVisit" {
-                        arrivals.push(ExpectedBusArrival::new_from_element(
-                            &mut reader,
-                            element,
-                            b"MonitoredStopVisit",
-                        )?)
+                Event::Start(element) if element.name().as_ref() == b"MonitoredStopVisit" => {
+                    match ExpectedBusArrival::new_from_element(
+                        &mut reader,
+                        element,
+                        b"MonitoredStopVisit",
+                    ) {
+                        Ok(arrival) => arrivals.push(arrival),
+                        Err(err) => match err.downcast::<VisitError>() {
+                            Ok(error) if !config.strict => {
+                                warnings.push(ParseWarning { index, error })
+                            }
+                            Ok(error) => return Err(error.into()),
+                            Err(err) => return Err(err),
+                        },
                     }
+                    index += 1;
+                }
+                // Both the ServiceDelivery and each StopMonitoringDelivery
+                // carry a Status, and either can be the one that failed.
+                Event::Start(element) if element.name().as_ref() == b"Status" => {
+                    status &= reader.read_text(element.name())?.trim() != "false";
+                }
+                Event::Start(element) if element.name().as_ref() == b"ErrorCondition" => {
+                    let condition = SiriError::new_from_element(&mut reader)?;
+                    error.get_or_insert(condition);
                 }
                 Event::Eof => break,
                 _ => (),
             }
         }
 
-        let minutes_away = dotenvy::var("MINUTES_AWAY")?.parse::<u32>()?;
-        let arrivals = arrivals
-            .iter()
-            .filter(|arrival| arrival.minutes_from_now().unwrap() >= minutes_away)
-            .take(3)
-            .cloned()
-            .collect();
+        if !status {
+            return Err(error
+                .unwrap_or_else(|| SiriError::new(SiriErrorKind::Other))
+                .into());
+        }
 
-        Ok(BusArrivalsLookup { arrivals })
+        Ok(BusArrivalsLookup { arrivals, warnings })
     }
 
     pub fn arrivals(&self) -> &[ExpectedBusArrival] {
         &self.arrivals
     }
+
+    pub fn warnings(&self) -> &[ParseWarning] {
+        &self.warnings
+    }
+
+    pub fn grouped(&self, grouping: Grouping, group_size: usize) -> Vec<ArrivalGroup> {
+        group_arrivals(&self.arrivals, grouping, group_size)
+    }
 }
 
 #[cfg(test)]
@@ -157,33 +937,679 @@ mod tests {
     use super::*;
     use rstest::*;
 
+    fn config() -> NextBusesConfig {
+        NextBusesConfig::new(vec![BusStop {
+            naptan_code: "45242629".to_string(),
+            minutes_away: 0,
+        }])
+        .set_credentials("user".to_string(), "pass".to_string())
+    }
+
+    fn clock() -> FixedClock {
+        FixedClock(DateTime::parse_from_rfc3339("2024-03-09T15:21:17.555Z").unwrap())
+    }
+
     #[rstest]
     pub fn parse_response_to_lookup(xml_response: &str) {
+        let config = NextBusesConfig {
+            strict: true,
+            max_results: 3,
+            ..config()
+        };
+        // What every bus in the response has in common.
+        let parsed = |line: &str, expected_time: &str| ExpectedBusArrival {
+            stop: Some("45242629".to_string()),
+            vehicle_mode: Some(VehicleMode::Bus),
+            recorded_at: Some(DateTime::parse_from_rfc3339("2024-03-09T15:21:17.555Z").unwrap()),
+            ..ExpectedBusArrival::example(line, expected_time)
+        };
         let expected_lookup = BusArrivalsLookup {
+            warnings: vec![],
             arrivals: vec![
                 ExpectedBusArrival {
-                    line: "17".to_string(),
-                    expected_time: DateTime::parse_from_rfc3339("2024-03-09T15:26:18.000Z")
-                        .unwrap(),
+                    direction: Some("Central Station Union St".to_string()),
+                    atco_code: Some("6090235412".to_string()),
+                    aimed_time: Some(
+                        DateTime::parse_from_rfc3339("2024-03-09T15:15:00.000Z").unwrap(),
+                    ),
+                    operator: Some("WCMO".to_string()),
+                    ..parsed("17", "2024-03-09T15:26:18.000Z")
                 },
                 ExpectedBusArrival {
-                    line: "61".to_string(),
-                    expected_time: DateTime::parse_from_rfc3339("2024-03-09T15:35:44.000Z")
-                        .unwrap(),
+                    direction: Some("Sandyhills Balbeggie St".to_string()),
+                    aimed_time: Some(
+                        DateTime::parse_from_rfc3339("2024-03-09T15:30:00.000Z").unwrap(),
+                    ),
+                    operator: Some("FG".to_string()),
+                    ..parsed("61", "2024-03-09T15:35:44.000Z")
                 },
                 ExpectedBusArrival {
-                    line: "60A".to_string(),
-                    expected_time: DateTime::parse_from_rfc3339("2024-03-09T15:35:59.000Z")
-                        .unwrap(),
+                    direction: Some("Easterhouse Terminus".to_string()),
+                    aimed_time: Some(
+                        DateTime::parse_from_rfc3339("2024-03-09T15:23:00.000Z").unwrap(),
+                    ),
+                    operator: Some("FG".to_string()),
+                    ..parsed("60A", "2024-03-09T15:35:59.000Z")
                 },
             ],
         };
         assert_eq!(
-            BusArrivalsLookup::from_xml(xml_response).unwrap(),
+            BusArrivalsLookup::from_xml(xml_response, &config, &config.stops[0], &clock()).unwrap(),
             expected_lookup
         );
     }
 
+    #[rstest]
+    #[case(
+        "2024-03-09T15:30:00.000Z",
+        "2024-03-09T15:35:44.000Z",
+        5,
+        Punctuality::Late
+    )]
+    #[case(
+        "2024-03-09T15:15:00.000Z",
+        "2024-03-09T15:26:18.000Z",
+        11,
+        Punctuality::VeryLate
+    )]
+    #[case(
+        "2024-03-09T15:38:00.000Z",
+        "2024-03-09T15:38:19.000Z",
+        0,
+        Punctuality::OnTime
+    )]
+    #[case("2024-03-09T15:38:00.000Z", "2024-03-09T15:36:00.000Z", -2, Punctuality::OnTime)]
+    pub fn punctuality_from_aimed_and_expected_times(
+        #[case] aimed_time: &str,
+        #[case] expected_time: &str,
+        #[case] delay_minutes: i64,
+        #[case] punctuality: Punctuality,
+    ) {
+        let arrival = ExpectedBusArrival {
+            aimed_time: Some(DateTime::parse_from_rfc3339(aimed_time).unwrap()),
+            ..ExpectedBusArrival::example("61", expected_time)
+        };
+        assert_eq!(arrival.delay().num_minutes(), delay_minutes);
+        assert_eq!(arrival.punctuality(), punctuality);
+    }
+
+    fn parse_visit(xml: &str) -> ExpectedBusArrival {
+        let mut reader = Reader::from_str(xml);
+        reader.trim_text(true);
+        let mut buf = Vec::new();
+        loop {
+            match reader.read_event_into(&mut buf).unwrap() {
+                Event::Start(element) if element.name().as_ref() == b"MonitoredStopVisit" => {
+                    return ExpectedBusArrival::new_from_element(
+                        &mut reader,
+                        element,
+                        b"MonitoredStopVisit",
+                    )
+                    .unwrap()
+                }
+                Event::Eof => panic!("no MonitoredStopVisit"),
+                _ => (),
+            }
+        }
+    }
+
+    #[rstest]
+    pub fn parse_response_with_walking_time_and_filter(xml_response: &str) {
+        let config = NextBusesConfig {
+            stops: vec![BusStop {
+                naptan_code: "45242629".to_string(),
+                minutes_away: 12,
+            }],
+            filter: ArrivalFilter {
+                exclude_lines: vec!["17".to_string()],
+                ..Default::default()
+            },
+            max_results: 2,
+            ..config()
+        };
+        let lookup =
+            BusArrivalsLookup::from_xml(xml_response, &config, &config.stops[0], &clock()).unwrap();
+        let lines: Vec<_> = lookup
+            .arrivals()
+            .iter()
+            .map(|arrival| (arrival.line.as_str(), arrival.walking_minutes))
+            .collect();
+        assert_eq!(lines, vec![("61", 12), ("60A", 12)]);
+    }
+
+    #[fixture]
+    pub fn malformed_xml_response() -> &'static str {
+        r#"<Siri><ServiceDelivery><StopMonitoringDelivery>
+            <MonitoredStopVisit><MonitoredVehicleJourney>
+                <MonitoredCall>
+                    <ExpectedDepartureTime>2024-03-09T15:26:18.000Z</ExpectedDepartureTime>
+                </MonitoredCall>
+            </MonitoredVehicleJourney></MonitoredStopVisit>
+            <MonitoredStopVisit><MonitoredVehicleJourney>
+                <PublishedLineName>61</PublishedLineName>
+                <MonitoredCall>
+                    <ExpectedDepartureTime>2024-03-09T15:35:44.000Z</ExpectedDepartureTime>
+                </MonitoredCall>
+            </MonitoredVehicleJourney></MonitoredStopVisit>
+            <MonitoredStopVisit><MonitoredVehicleJourney>
+                <PublishedLineName>60A</PublishedLineName>
+                <MonitoredCall>
+                    <ExpectedDepartureTime>half past three</ExpectedDepartureTime>
+                </MonitoredCall>
+            </MonitoredVehicleJourney></MonitoredStopVisit>
+            <MonitoredStopVisit><MonitoredVehicleJourney>
+                <PublishedLineName>60</PublishedLineName>
+            </MonitoredVehicleJourney></MonitoredStopVisit>
+            <MonitoredStopVisit><MonitoredVehicleJourney>
+                <PublishedLineName>X10</PublishedLineName>
+                <MonitoredCall>
+                    <AimedDepartureTime>2024-03-09T15:46:00.000Z</AimedDepartureTime>
+                </MonitoredCall>
+            </MonitoredVehicleJourney></MonitoredStopVisit>
+        </StopMonitoringDelivery></ServiceDelivery></Siri>"#
+    }
+
+    #[rstest]
+    pub fn skips_malformed_visits_with_warnings(malformed_xml_response: &str) {
+        let config = config();
+        let lookup = BusArrivalsLookup::from_xml(
+            malformed_xml_response,
+            &config,
+            &config.stops[0],
+            &clock(),
+        )
+        .unwrap();
+        let lines: Vec<_> = lookup
+            .arrivals()
+            .iter()
+            .map(|arrival| arrival.line.as_str())
+            .collect();
+        assert_eq!(lines, vec!["61", "X10"]);
+        assert_eq!(
+            lookup.warnings(),
+            &[
+                ParseWarning {
+                    index: 0,
+                    error: VisitError::Missing("PublishedLineName"),
+                },
+                ParseWarning {
+                    index: 2,
+                    error: VisitError::Invalid("ExpectedDepartureTime"),
+                },
+                ParseWarning {
+                    index: 3,
+                    error: VisitError::Missing("AimedDepartureTime"),
+                },
+            ]
+        );
+    }
+
+    #[rstest]
+    pub fn strict_mode_fails_on_malformed_visits(malformed_xml_response: &str) {
+        let config = NextBusesConfig {
+            strict: true,
+            ..config()
+        };
+        let err = BusArrivalsLookup::from_xml(
+            malformed_xml_response,
+            &config,
+            &config.stops[0],
+            &clock(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<VisitError>(),
+            Some(&VisitError::Missing("PublishedLineName"))
+        );
+    }
+
+    #[rstest]
+    #[case("<AccessNotAllowedError/>", SiriErrorKind::AuthFailed)]
+    #[case("<InvalidDataReferencesError/>", SiriErrorKind::UnknownStop)]
+    #[case(
+        "<AllowedResourceUsageExceededError><ErrorText>Slow down</ErrorText></AllowedResourceUsageExceededError>",
+        SiriErrorKind::OverQuota
+    )]
+    #[case("<OtherError/>", SiriErrorKind::Other)]
+    pub fn parses_error_conditions(#[case] condition: &str, #[case] kind: SiriErrorKind) {
+        let xml = format!(
+            "<Siri><ServiceDelivery><Status>false</Status><ErrorCondition>{}</ErrorCondition></ServiceDelivery></Siri>",
+            condition
+        );
+        let config = config();
+        let err =
+            BusArrivalsLookup::from_xml(&xml, &config, &config.stops[0], &clock()).unwrap_err();
+        assert_eq!(err.downcast_ref::<SiriError>().unwrap().kind, kind);
+    }
+
+    #[rstest]
+    pub fn keeps_the_provider_error_description() {
+        let xml = r#"<Siri><ServiceDelivery><Status>true</Status><StopMonitoringDelivery>
+            <Status>false</Status>
+            <ErrorCondition>
+                <AccessNotAllowedError><ErrorText>Forbidden</ErrorText></AccessNotAllowedError>
+                <Description>Invalid username or password</Description>
+            </ErrorCondition>
+        </StopMonitoringDelivery></ServiceDelivery></Siri>"#;
+        let config = config();
+        let err =
+            BusArrivalsLookup::from_xml(xml, &config, &config.stops[0], &clock()).unwrap_err();
+        let err = err.downcast_ref::<SiriError>().unwrap();
+        assert_eq!(err.summary(), "Auth failed");
+        assert_eq!(err.to_string(), "Auth failed: Invalid username or password");
+    }
+
+    #[rstest]
+    pub fn only_needs_credentials_for_nextbuses() {
+        let anonymous = NextBusesConfig {
+            api_pass: None,
+            ..config()
+        };
+        assert!(anonymous.credentials().is_err());
+        assert_eq!(config().credentials().unwrap(), ("user", "pass"));
+        assert_eq!(
+            NextBusesConfig::new(vec![])
+                .credentials()
+                .unwrap_err()
+                .to_string(),
+            "NEXT_BUSES_API_USER and NEXT_BUSES_API_PASS are needed for NextBuses"
+        );
+    }
+
+    #[rstest]
+    pub fn builds_request_from_config() {
+        let config = NextBusesConfig {
+            preview_interval: Some(90),
+            filter: ArrivalFilter {
+                include_lines: vec!["60A".to_string()],
+                ..ArrivalFilter::default()
+            },
+            ..config()
+        };
+        let xml = config
+            .stop_monitoring_request(&config.stops[0], &clock())
+            .to_xml()
+            .unwrap();
+        assert!(xml.contains("<PreviewInterval>PT90M</PreviewInterval>"));
+        assert!(xml.contains("<MonitoringRef>45242629</MonitoringRef>"));
+        assert!(xml.contains("<LineRef>60A</LineRef>"));
+        assert!(!xml.contains("MaximumStopVisits"));
+    }
+
+    #[rstest]
+    #[case("45242629", 5, "45242629", 5)]
+    #[case("45242630:8", 5, "45242630", 8)]
+    pub fn parses_bus_stop(
+        #[case] stop: &str,
+        #[case] default_minutes_away: u32,
+        #[case] naptan_code: &str,
+        #[case] minutes_away: u32,
+    ) {
+        assert_eq!(
+            BusStop::parse(stop, default_minutes_away).unwrap(),
+            BusStop {
+                naptan_code: naptan_code.to_string(),
+                minutes_away,
+            }
+        );
+    }
+
+    #[rstest]
+    pub fn parses_bus_stop_list() {
+        let stops = BusStop::parse_list("45242629, 45242630:8,", 5).unwrap();
+        assert_eq!(
+            stops,
+            vec![
+                BusStop {
+                    naptan_code: "45242629".to_string(),
+                    minutes_away: 5,
+                },
+                BusStop {
+                    naptan_code: "45242630".to_string(),
+                    minutes_away: 8,
+                },
+            ]
+        );
+    }
+
+    #[rstest]
+    pub fn merges_arrivals_from_stops_by_time() {
+        let at = |stop: &str, time: &str| ExpectedBusArrival {
+            stop: Some(stop.to_string()),
+            ..ExpectedBusArrival::example("61", time)
+        };
+        let merged = merge_arrivals(
+            vec![
+                at("A", "2024-03-09T15:40:00.000Z"),
+                at("A", "2024-03-09T15:50:00.000Z"),
+                at("B", "2024-03-09T15:30:00.000Z"),
+                at("B", "2024-03-09T15:45:00.000Z"),
+            ],
+            3,
+        );
+        let stops: Vec<_> = merged
+            .iter()
+            .map(|arrival| arrival.stop.as_deref().unwrap())
+            .collect();
+        assert_eq!(stops, vec!["B", "A", "B"]);
+    }
+
+    // Has a bus at every stop but the ones it's told are down.
+    struct PartlyDown {
+        down: Vec<&'static str>,
+    }
+
+    #[async_trait]
+    impl TransitSource for PartlyDown {
+        fn name(&self) -> &str {
+            "partly-down"
+        }
+
+        async fn stop_visits(
+            &self,
+            _config: &NextBusesConfig,
+            stop: &BusStop,
+            _clock: &dyn Clock,
+        ) -> Result<Vec<ExpectedBusArrival>> {
+            if self.down.contains(&stop.naptan_code.as_str()) {
+                return Err(anyhow!("Timed out"));
+            }
+            Ok(vec![ExpectedBusArrival {
+                stop: Some(stop.naptan_code.clone()),
+                ..ExpectedBusArrival::example("61", "2024-03-09T15:30:00.000Z")
+            }])
+        }
+    }
+
+    #[rstest]
+    #[case(vec![], Some(vec!["A", "B"]))]
+    #[case(vec!["B"], Some(vec!["A"]))]
+    #[case(vec!["A", "B"], None)]
+    #[tokio::test]
+    pub async fn skips_stops_that_fail(
+        #[case] down: Vec<&'static str>,
+        #[case] expected: Option<Vec<&str>>,
+    ) {
+        let stop = |naptan_code: &str| BusStop {
+            naptan_code: naptan_code.to_string(),
+            minutes_away: 0,
+        };
+        let config = NextBusesConfig::new(vec![stop("A"), stop("B")]);
+        let arrivals = get_next_buses(Arc::new(PartlyDown { down }), &config, &clock()).await;
+        let stops = arrivals.ok().map(|arrivals| {
+            let mut stops: Vec<_> = arrivals
+                .iter()
+                .map(|arrival| arrival.stop.clone().unwrap())
+                .collect();
+            stops.sort();
+            stops
+        });
+        assert_eq!(
+            stops,
+            expected.map(|stops| stops.iter().map(|stop| stop.to_string()).collect())
+        );
+    }
+
+    #[rstest]
+    pub fn parses_direction_name() {
+        let arrival = parse_visit(
+            r#"<MonitoredStopVisit><MonitoredVehicleJourney>
+                <PublishedLineName>60A</PublishedLineName>
+                <DirectionName>Easterhouse Terminus</DirectionName>
+                <MonitoredCall>
+                    <AimedDepartureTime>2024-03-09T15:23:00.000Z</AimedDepartureTime>
+                </MonitoredCall>
+            </MonitoredVehicleJourney></MonitoredStopVisit>"#,
+        );
+        assert_eq!(arrival.direction.as_deref(), Some("Easterhouse Terminus"));
+    }
+
+    fn arrival_on(line: &str, direction: &str) -> ExpectedBusArrival {
+        ExpectedBusArrival {
+            direction: Some(direction.to_string()),
+            ..ExpectedBusArrival::example(line, "2024-03-09T15:35:59.000Z")
+        }
+    }
+
+    #[rstest]
+    #[case(ArrivalFilter::default(), true)]
+    #[case(ArrivalFilter { include_lines: vec!["60a".into(), "61".into()], ..Default::default() }, true)]
+    #[case(ArrivalFilter { include_lines: vec!["60".into()], ..Default::default() }, false)]
+    #[case(ArrivalFilter { exclude_lines: vec!["60A".into()], ..Default::default() }, false)]
+    #[case(ArrivalFilter { include_directions: vec!["easterhouse".into()], ..Default::default() }, true)]
+    #[case(ArrivalFilter { include_directions: vec!["Central".into()], ..Default::default() }, false)]
+    #[case(ArrivalFilter { exclude_directions: vec!["Lochdochart".into()], ..Default::default() }, false)]
+    pub fn filters_by_line_and_direction(#[case] filter: ArrivalFilter, #[case] shown: bool) {
+        let arrival = arrival_on("60A", "Easterhouse, Lochdochart Road Terminus (unmarked)");
+        assert_eq!(filter.matches(&arrival), shown);
+    }
+
+    #[rstest]
+    pub fn visits_with_expected_time_are_real_time() {
+        let arrival = parse_visit(
+            r#"<MonitoredStopVisit><MonitoredVehicleJourney>
+                <PublishedLineName>61</PublishedLineName>
+                <MonitoredCall>
+                    <AimedDepartureTime>2024-03-09T15:30:00.000Z</AimedDepartureTime>
+                    <ExpectedDepartureTime>2024-03-09T15:35:44.000Z</ExpectedDepartureTime>
+                </MonitoredCall>
+            </MonitoredVehicleJourney></MonitoredStopVisit>"#,
+        );
+        assert!(arrival.real_time);
+        assert_eq!(
+            arrival.expected_time,
+            DateTime::parse_from_rfc3339("2024-03-09T15:35:44.000Z").unwrap()
+        );
+    }
+
+    #[rstest]
+    pub fn visits_with_only_aimed_time_are_scheduled() {
+        let arrival = parse_visit(
+            r#"<MonitoredStopVisit><MonitoredVehicleJourney>
+                <PublishedLineName>60A</PublishedLineName>
+                <MonitoredCall>
+                    <AimedDepartureTime>2024-03-09T16:23:00.000Z</AimedDepartureTime>
+                </MonitoredCall>
+            </MonitoredVehicleJourney></MonitoredStopVisit>"#,
+        );
+        assert!(!arrival.real_time);
+        assert_eq!(
+            arrival.expected_time,
+            DateTime::parse_from_rfc3339("2024-03-09T16:23:00.000Z").unwrap()
+        );
+    }
+
+    #[rstest]
+    #[case("cancelled", Some(DepartureStatus::Cancelled))]
+    #[case("departed", Some(DepartureStatus::Departed))]
+    #[case("delayed", Some(DepartureStatus::Delayed))]
+    #[case("noReport", Some(DepartureStatus::NoReport))]
+    #[case("teleported", None)]
+    pub fn parses_departure_status(
+        #[case] status: &str,
+        #[case] expected: Option<DepartureStatus>,
+    ) {
+        let arrival = parse_visit(&format!(
+            r#"<MonitoredStopVisit><MonitoredVehicleJourney>
+                <PublishedLineName>60A</PublishedLineName>
+                <MonitoredCall>
+                    <ExpectedDepartureTime>2024-03-09T15:26:18.000Z</ExpectedDepartureTime>
+                    <DepartureStatus>{}</DepartureStatus>
+                </MonitoredCall>
+            </MonitoredVehicleJourney></MonitoredStopVisit>"#,
+            status
+        ));
+        assert_eq!(arrival.departure_status, expected);
+    }
+
+    #[rstest]
+    pub fn hides_departed_buses_but_keeps_cancelled_ones() {
+        let with_status = |line: &str, departure_status| ExpectedBusArrival {
+            departure_status,
+            ..arrival_on(line, "Easterhouse")
+        };
+        let config = config();
+        let arrivals = select_arrivals(
+            vec![
+                with_status("60A", Some(DepartureStatus::Departed)),
+                with_status("61", Some(DepartureStatus::Cancelled)),
+                with_status("17", None),
+            ],
+            &config,
+            &config.stops[0],
+            &clock(),
+        );
+        let lines: Vec<_> = arrivals
+            .iter()
+            .map(|arrival| (arrival.line.as_str(), arrival.is_cancelled()))
+            .collect();
+        assert_eq!(lines, vec![("61", true), ("17", false)]);
+    }
+
+    #[rstest]
+    pub fn does_not_count_cancelled_buses_against_the_limit() {
+        let cancelled = |line: &str| ExpectedBusArrival {
+            departure_status: Some(DepartureStatus::Cancelled),
+            ..arrival_on(line, "Easterhouse")
+        };
+        let config = NextBusesConfig {
+            max_results: 2,
+            ..config()
+        };
+        let arrivals = select_arrivals(
+            vec![
+                cancelled("60A"),
+                arrival_on("61", "Easterhouse"),
+                cancelled("17"),
+                arrival_on("38", "Easterhouse"),
+                arrival_on("62", "Easterhouse"),
+            ],
+            &config,
+            &config.stops[0],
+            &clock(),
+        );
+        let lines: Vec<_> = arrivals
+            .iter()
+            .map(|arrival| arrival.line.as_str())
+            .collect();
+        assert_eq!(lines, vec!["60A", "61", "17", "38"]);
+    }
+
+    #[rstest]
+    #[case(Grouping::Line, vec![("60A", None, 3), ("61", None, 1)])]
+    #[case(
+        Grouping::LineAndDirection,
+        vec![
+            ("60A", Some("Easterhouse"), 3),
+            ("61", Some("Sandyhills"), 1),
+            ("60A", Some("Maryhill"), 2),
+        ]
+    )]
+    pub fn groups_arrivals_by_line(
+        #[case] grouping: Grouping,
+        #[case] expected: Vec<(&str, Option<&str>, usize)>,
+    ) {
+        let at = |line: &str, direction: &str, minutes: i64| ExpectedBusArrival {
+            expected_time: clock().now() + Duration::minutes(minutes),
+            ..arrival_on(line, direction)
+        };
+        let arrivals = vec![
+            at("60A", "Easterhouse", 3),
+            at("61", "Sandyhills", 5),
+            at("60A", "Maryhill", 8),
+            at("60A", "Easterhouse", 12),
+            ExpectedBusArrival {
+                departure_status: Some(DepartureStatus::Cancelled),
+                ..at("60A", "Easterhouse", 14)
+            },
+            at("60A", "Maryhill", 20),
+            at("60A", "Easterhouse", 27),
+        ];
+        let groups: Vec<_> = group_arrivals(&arrivals, grouping, 3)
+            .into_iter()
+            .map(|group| (group.line, group.direction, group.arrivals.len()))
+            .collect();
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(line, direction, count)| (line.to_string(), direction.map(String::from), count))
+            .collect();
+        assert_eq!(groups, expected);
+    }
+
+    #[rstest]
+    pub fn grouped_lookup_keeps_every_bus_until_grouped(xml_response: &str) {
+        let config = NextBusesConfig {
+            max_results: 1,
+            grouping: Some(Grouping::Line),
+            ..config()
+        };
+        let lookup =
+            BusArrivalsLookup::from_xml(xml_response, &config, &config.stops[0], &clock()).unwrap();
+        let lines: Vec<_> = lookup
+            .grouped(Grouping::Line, 2)
+            .into_iter()
+            .map(|group| group.line)
+            .collect();
+        assert_eq!(lines[..3], ["17", "61", "60A"]);
+    }
+
+    #[rstest]
+    #[case("FG", "FG")]
+    #[case("_noc_FGLA", "FGLA")]
+    #[case("_NOC_mcgl", "MCGL")]
+    #[case(" WCMO ", "WCMO")]
+    pub fn normalises_operator_refs(#[case] operator_ref: &str, #[case] expected: &str) {
+        assert_eq!(normalise_operator(operator_ref), expected);
+    }
+
+    #[rstest]
+    pub fn parses_operator_mode_and_recorded_time() {
+        let arrival = parse_visit(
+            r#"<MonitoredStopVisit>
+            <RecordedAtTime>2024-03-09T15:21:17.555Z</RecordedAtTime>
+            <MonitoredVehicleJourney>
+                <VehicleMode>tram</VehicleMode>
+                <PublishedLineName>61</PublishedLineName>
+                <OperatorRef>_noc_FGLA</OperatorRef>
+                <MonitoredCall>
+                    <AimedDepartureTime>2024-03-09T15:30:00.000Z</AimedDepartureTime>
+                </MonitoredCall>
+            </MonitoredVehicleJourney></MonitoredStopVisit>"#,
+        );
+        assert_eq!(arrival.operator.as_deref(), Some("FGLA"));
+        assert_eq!(arrival.vehicle_mode, Some(VehicleMode::Tram));
+        assert_eq!(
+            arrival.recorded_at,
+            Some(DateTime::parse_from_rfc3339("2024-03-09T15:21:17.555Z").unwrap())
+        );
+    }
+
+    #[rstest]
+    // Leave at 15:25:59, about 4m41s from now.
+    #[case(10, 5, true)]
+    #[case(10, 4, false)]
+    // Should have left at 15:20:59.
+    #[case(15, 5, false)]
+    pub fn leaves_within_window(
+        #[case] walking_minutes: u32,
+        #[case] window: i64,
+        #[case] leave_now: bool,
+    ) {
+        let arrival = ExpectedBusArrival {
+            walking_minutes,
+            ..arrival_on("60A", "Easterhouse")
+        };
+        assert_eq!(
+            arrival.leave_time(),
+            DateTime::parse_from_rfc3339("2024-03-09T15:35:59.000Z").unwrap()
+                - Duration::minutes(walking_minutes.into())
+        );
+        assert_eq!(
+            arrival.leave_within(Duration::minutes(window), &clock()),
+            leave_now
+        );
+    }
+
     #[fixture]
     pub fn xml_response() -> &'static str {
         r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
@@ -206,6 +1632,7 @@ mod tests {
                     <DirectionName>Central Station Union St</DirectionName>
                     <OperatorRef>WCMO</OperatorRef>
                     <MonitoredCall>
+                        <StopPointRef>6090235412</StopPointRef>
                         <AimedDepartureTime>2024-03-09T15:15:00.000Z</AimedDepartureTime>
                         <ExpectedDepartureTime>2024-03-09T15:26:18.000Z</ExpectedDepartureTime>
                     </MonitoredCall>
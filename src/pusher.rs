@@ -15,7 +15,9 @@ pub mod pusher {
         background: bool,
     }
 
-    pub async fn push(file_contents: &Vec<u8>) -> Result<bool> {
+    /// Pushes the image to the Tidbyt. In the background it joins the
+    /// rotation of apps; otherwise it's shown straight away.
+    pub async fn push(file_contents: &Vec<u8>, background: bool) -> Result<bool> {
         let base64_string = general_purpose::STANDARD.encode(file_contents);
         let device_id = dotenvy::var("TIDBYT_ID").expect("Missing TIDBYT_ID");
         let tidbyt_key = dotenvy::var("TIDBYT_KEY").expect("Missing TIDBYT_KEY");
@@ -25,7 +27,7 @@ pub mod pusher {
             device_id,
             image: base64_string.clone(),
             installation_id: "custom".into(),
-            background: true,
+            background,
         };
 
         let resp = reqwest::Client::new()
@@ -0,0 +1,277 @@
+pub mod siri_request {
+    use anyhow::Result;
+    use chrono::{DateTime, FixedOffset};
+    use quick_xml::events::{BytesDecl, BytesText, Event};
+    use quick_xml::Writer;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    // Distinguishes requests made in the same millisecond, e.g. when
+    // several stops are looked up at once.
+    static MESSAGE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    /// A message id that no other request from this machine will share.
+    pub fn message_identifier(timestamp: &DateTime<FixedOffset>) -> String {
+        format!(
+            "tidbyt-{}-{}-{}",
+            std::process::id(),
+            timestamp.timestamp_millis(),
+            MESSAGE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        )
+    }
+
+    /// A SIRI `StopMonitoringRequest` for a single stop.
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct StopMonitoringRequest {
+        requestor_ref: String,
+        monitoring_ref: String,
+        timestamp: DateTime<FixedOffset>,
+        message_identifier: String,
+        preview_interval: Option<u32>,
+        maximum_stop_visits: Option<usize>,
+        line_ref: Option<String>,
+    }
+
+    impl StopMonitoringRequest {
+        pub fn new(
+            requestor_ref: String,
+            monitoring_ref: String,
+            timestamp: DateTime<FixedOffset>,
+        ) -> StopMonitoringRequest {
+            StopMonitoringRequest {
+                requestor_ref,
+                monitoring_ref,
+                message_identifier: message_identifier(&timestamp),
+                timestamp,
+                preview_interval: None,
+                maximum_stop_visits: None,
+                line_ref: None,
+            }
+        }
+
+        pub fn set_message_identifier(mut self, message_identifier: String) -> Self {
+            self.message_identifier = message_identifier;
+            self
+        }
+
+        /// How many minutes ahead to look for departures.
+        pub fn set_preview_interval(mut self, minutes: Option<u32>) -> Self {
+            self.preview_interval = minutes;
+            self
+        }
+
+        pub fn set_maximum_stop_visits(mut self, maximum_stop_visits: Option<usize>) -> Self {
+            self.maximum_stop_visits = maximum_stop_visits;
+            self
+        }
+
+        /// Only ask for departures on this line.
+        pub fn set_line_ref(mut self, line_ref: Option<String>) -> Self {
+            self.line_ref = line_ref;
+            self
+        }
+
+        pub fn message_identifier(&self) -> &str {
+            &self.message_identifier
+        }
+
+        pub fn to_xml(&self) -> Result<String> {
+            let timestamp = self.timestamp.to_rfc3339();
+            service_request(
+                &self.requestor_ref,
+                &timestamp,
+                "StopMonitoringRequest",
+                |writer| self.write_body(writer, &timestamp),
+            )
+        }
+
+        // The SIRI schema fixes the order of these elements.
+        fn write_body(&self, writer: &mut Writer<Vec<u8>>, timestamp: &str) -> Result<()> {
+            text_element(writer, "RequestTimestamp", timestamp)?;
+            text_element(writer, "MessageIdentifier", &self.message_identifier)?;
+            if let Some(minutes) = self.preview_interval {
+                text_element(writer, "PreviewInterval", &format!("PT{}M", minutes))?;
+            }
+            text_element(writer, "MonitoringRef", &self.monitoring_ref)?;
+            if let Some(line_ref) = &self.line_ref {
+                text_element(writer, "LineRef", line_ref)?;
+            }
+            if let Some(maximum_stop_visits) = self.maximum_stop_visits {
+                text_element(
+                    writer,
+                    "MaximumStopVisits",
+                    &maximum_stop_visits.to_string(),
+                )?;
+            }
+            Ok(())
+        }
+    }
+
+    /// A SIRI `SituationExchangeRequest` for every current disruption
+    /// the requestor can see.
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct SituationExchangeRequest {
+        requestor_ref: String,
+        timestamp: DateTime<FixedOffset>,
+        message_identifier: String,
+    }
+
+    impl SituationExchangeRequest {
+        pub fn new(
+            requestor_ref: String,
+            timestamp: DateTime<FixedOffset>,
+        ) -> SituationExchangeRequest {
+            SituationExchangeRequest {
+                requestor_ref,
+                message_identifier: message_identifier(&timestamp),
+                timestamp,
+            }
+        }
+
+        pub fn set_message_identifier(mut self, message_identifier: String) -> Self {
+            self.message_identifier = message_identifier;
+            self
+        }
+
+        pub fn to_xml(&self) -> Result<String> {
+            let timestamp = self.timestamp.to_rfc3339();
+            service_request(
+                &self.requestor_ref,
+                &timestamp,
+                "SituationExchangeRequest",
+                |writer| {
+                    text_element(writer, "RequestTimestamp", &timestamp)?;
+                    text_element(writer, "MessageIdentifier", &self.message_identifier)
+                },
+            )
+        }
+    }
+
+    // Wraps a request in the `Siri` and `ServiceRequest` envelope.
+    fn service_request(
+        requestor_ref: &str,
+        timestamp: &str,
+        request: &str,
+        write_body: impl FnOnce(&mut Writer<Vec<u8>>) -> Result<()>,
+    ) -> Result<String> {
+        let mut writer = Writer::new(Vec::new());
+        writer.write_event(Event::Decl(BytesDecl::new(
+            "1.0",
+            Some("UTF-8"),
+            Some("yes"),
+        )))?;
+        writer
+            .create_element("Siri")
+            .with_attribute(("version", "1.0"))
+            .with_attribute(("xmlns", "http://www.siri.org.uk/"))
+            .write_inner_content::<_, anyhow::Error>(|writer| {
+                writer
+                    .create_element("ServiceRequest")
+                    .write_inner_content::<_, anyhow::Error>(|writer| {
+                        text_element(writer, "RequestTimestamp", timestamp)?;
+                        text_element(writer, "RequestorRef", requestor_ref)?;
+                        writer
+                            .create_element(request)
+                            .with_attribute(("version", "1.0"))
+                            .write_inner_content(write_body)?;
+                        Ok(())
+                    })?;
+                Ok(())
+            })?;
+        Ok(String::from_utf8(writer.into_inner())?)
+    }
+
+    fn text_element(writer: &mut Writer<Vec<u8>>, name: &str, text: &str) -> Result<()> {
+        writer
+            .create_element(name)
+            .write_text_content(BytesText::new(text))?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use rstest::*;
+
+        fn request() -> StopMonitoringRequest {
+            StopMonitoringRequest::new(
+                "user".to_string(),
+                "45242629".to_string(),
+                DateTime::parse_from_rfc3339("2024-03-09T15:21:17Z").unwrap(),
+            )
+            .set_message_identifier("1".to_string())
+        }
+
+        #[rstest]
+        pub fn writes_minimal_request() {
+            assert_eq!(
+                request().to_xml().unwrap(),
+                concat!(
+                    r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+                    r#"<Siri version="1.0" xmlns="http://www.siri.org.uk/"><ServiceRequest>"#,
+                    "<RequestTimestamp>2024-03-09T15:21:17+00:00</RequestTimestamp>",
+                    "<RequestorRef>user</RequestorRef>",
+                    r#"<StopMonitoringRequest version="1.0">"#,
+                    "<RequestTimestamp>2024-03-09T15:21:17+00:00</RequestTimestamp>",
+                    "<MessageIdentifier>1</MessageIdentifier>",
+                    "<MonitoringRef>45242629</MonitoringRef>",
+                    "</StopMonitoringRequest></ServiceRequest></Siri>"
+                )
+            );
+        }
+
+        #[rstest]
+        pub fn writes_optional_fields_in_schema_order() {
+            let xml = request()
+                .set_preview_interval(Some(60))
+                .set_maximum_stop_visits(Some(5))
+                .set_line_ref(Some("60A".to_string()))
+                .to_xml()
+                .unwrap();
+            assert!(xml.contains(
+                "<MessageIdentifier>1</MessageIdentifier>\
+                 <PreviewInterval>PT60M</PreviewInterval>\
+                 <MonitoringRef>45242629</MonitoringRef>\
+                 <LineRef>60A</LineRef>\
+                 <MaximumStopVisits>5</MaximumStopVisits>"
+            ));
+        }
+
+        #[rstest]
+        pub fn escapes_values() {
+            let xml = StopMonitoringRequest {
+                requestor_ref: "a<b>&\"c\"".to_string(),
+                ..request()
+            }
+            .to_xml()
+            .unwrap();
+            assert!(xml.contains("<RequestorRef>a&lt;b&gt;&amp;&quot;c&quot;</RequestorRef>"));
+        }
+
+        #[rstest]
+        pub fn writes_situation_exchange_request() {
+            let xml = SituationExchangeRequest::new(
+                "user".to_string(),
+                DateTime::parse_from_rfc3339("2024-03-09T15:21:17Z").unwrap(),
+            )
+            .set_message_identifier("2".to_string())
+            .to_xml()
+            .unwrap();
+            assert!(xml.contains(
+                "<RequestorRef>user</RequestorRef>\
+                 <SituationExchangeRequest version=\"1.0\">\
+                 <RequestTimestamp>2024-03-09T15:21:17+00:00</RequestTimestamp>\
+                 <MessageIdentifier>2</MessageIdentifier>\
+                 </SituationExchangeRequest>"
+            ));
+        }
+
+        #[rstest]
+        pub fn message_identifiers_are_unique() {
+            let timestamp = DateTime::parse_from_rfc3339("2024-03-09T15:21:17Z").unwrap();
+            assert_ne!(
+                message_identifier(&timestamp),
+                message_identifier(&timestamp)
+            );
+        }
+    }
+}
@@ -0,0 +1,257 @@
+pub mod situation_exchange {
+    use crate::clock::clock::Clock;
+    use crate::next_buses::{env_var, NextBusesConfig};
+    use crate::siri_request::siri_request::SituationExchangeRequest;
+    use anyhow::Result;
+    use chrono::{DateTime, FixedOffset};
+    use quick_xml::events::Event;
+    use quick_xml::Reader;
+    use reqwest::header::USER_AGENT;
+
+    /// A disruption from a SIRI-SX `PtSituationElement`, with the
+    /// lines and stops it affects.
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub struct Situation {
+        pub summary: String,
+        // False once the provider has marked the situation closed.
+        pub open: bool,
+        pub start_time: Option<DateTime<FixedOffset>>,
+        pub end_time: Option<DateTime<FixedOffset>>,
+        // Both the `LineRef` and `PublishedLineName` of each affected line.
+        pub lines: Vec<String>,
+        // Usually ATCO codes, rather than the NaPTAN SMS codes stops are
+        // configured with.
+        pub stops: Vec<String>,
+    }
+
+    impl Situation {
+        pub fn new_from_element(reader: &mut Reader<&[u8]>) -> Result<Situation> {
+            let mut buf = Vec::new();
+            let mut situation = Situation {
+                open: true,
+                ..Situation::default()
+            };
+            // Publication windows have start and end times too, and
+            // only the validity period says when it's happening.
+            let mut in_validity = false;
+
+            loop {
+                match reader.read_event_into(&mut buf)? {
+                    Event::Start(el) => match el.name().as_ref() {
+                        b"ValidityPeriod" => in_validity = true,
+                        b"Summary" if situation.summary.is_empty() => {
+                            situation.summary = reader.read_text(el.name())?.trim().to_string();
+                        }
+                        b"Progress" => {
+                            situation.open = reader.read_text(el.name())?.trim() != "closed";
+                        }
+                        b"StartTime" if in_validity => {
+                            situation.start_time =
+                                DateTime::parse_from_rfc3339(reader.read_text(el.name())?.trim())
+                                    .ok();
+                        }
+                        b"EndTime" if in_validity => {
+                            situation.end_time =
+                                DateTime::parse_from_rfc3339(reader.read_text(el.name())?.trim())
+                                    .ok();
+                        }
+                        b"LineRef" | b"PublishedLineName" => {
+                            situation
+                                .lines
+                                .push(reader.read_text(el.name())?.trim().to_string());
+                        }
+                        b"StopPointRef" => {
+                            situation
+                                .stops
+                                .push(reader.read_text(el.name())?.trim().to_string());
+                        }
+                        _ => (),
+                    },
+                    Event::End(el) if el.name().as_ref() == b"ValidityPeriod" => {
+                        in_validity = false
+                    }
+                    Event::End(el) if el.name().as_ref() == b"PtSituationElement" => break,
+                    Event::Eof => break,
+                    _ => (),
+                }
+            }
+            Ok(situation)
+        }
+
+        pub fn is_active(&self, clock: &dyn Clock) -> bool {
+            let now = clock.now();
+            self.open
+                && self.start_time.is_none_or(|start| start <= now)
+                && self.end_time.is_none_or(|end| now < end)
+        }
+
+        /// Whether this touches any of the stop codes in `stops`, or any
+        /// of `lines`. Line refs are often prefixed with the operator,
+        /// e.g. `FGLA:60A`.
+        pub fn affects(&self, stops: &[String], lines: &[String]) -> bool {
+            let stop_affected = stops.iter().any(|stop| self.stops.contains(stop));
+            let line_affected = self.lines.iter().any(|affected| {
+                let name = affected.rsplit(':').next().unwrap_or(affected);
+                lines.iter().any(|line| line.eq_ignore_ascii_case(name))
+            });
+            stop_affected || line_affected
+        }
+    }
+
+    /// Reads every `PtSituationElement` in a SIRI-SX delivery.
+    pub fn parse_situations(xml: &str) -> Result<Vec<Situation>> {
+        let mut reader = Reader::from_str(xml);
+        reader.trim_text(true);
+        let mut buf = Vec::new();
+        let mut situations = Vec::new();
+        loop {
+            match reader.read_event_into(&mut buf)? {
+                Event::Start(el) if el.name().as_ref() == b"PtSituationElement" => {
+                    situations.push(Situation::new_from_element(&mut reader)?);
+                }
+                Event::Eof => break,
+                _ => (),
+            }
+        }
+        Ok(situations)
+    }
+
+    /// The summaries of the situations worth showing, run together into
+    /// one line for a ticker.
+    pub fn ticker_text(situations: &[Situation]) -> Option<String> {
+        let summaries: Vec<&str> = situations
+            .iter()
+            .map(|situation| situation.summary.as_str())
+            .filter(|summary| !summary.is_empty())
+            .collect();
+        if summaries.is_empty() {
+            None
+        } else {
+            Some(summaries.join(" / "))
+        }
+    }
+
+    /// A SIRI-SX endpoint, using the same credentials as NextBuses.
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct SituationExchangeClient {
+        pub endpoint: String,
+    }
+
+    impl SituationExchangeClient {
+        /// Disruptions are only looked up when `SIRI_SX_ENDPOINT` is set.
+        pub fn from_env() -> Option<SituationExchangeClient> {
+            Some(SituationExchangeClient {
+                endpoint: env_var("SIRI_SX_ENDPOINT")?,
+            })
+        }
+
+        /// The situations active now that affect any of `stops`, by either
+        /// of their codes, or any of `lines`.
+        pub async fn get_situations(
+            &self,
+            config: &NextBusesConfig,
+            stops: &[String],
+            lines: &[String],
+            clock: &dyn Clock,
+        ) -> Result<Vec<Situation>> {
+            let (api_user, api_pass) = config.credentials()?;
+            let payload =
+                SituationExchangeRequest::new(api_user.to_string(), clock.now()).to_xml()?;
+            let api_response = reqwest::Client::new()
+                .post(&self.endpoint)
+                .basic_auth(api_user, Some(api_pass))
+                .body(payload)
+                .header(USER_AGENT, "tidbyt")
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            Ok(parse_situations(&api_response)?
+                .into_iter()
+                .filter(|situation| situation.is_active(clock))
+                .filter(|situation| situation.affects(stops, lines))
+                .collect())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::clock::clock::FixedClock;
+        use rstest::*;
+
+        #[fixture]
+        fn sx_response() -> &'static str {
+            include_str!("../fixtures/siri_sx.xml")
+        }
+
+        fn clock() -> FixedClock {
+            FixedClock(DateTime::parse_from_rfc3339("2024-03-09T15:21:17Z").unwrap())
+        }
+
+        // The stop by its SMS and ATCO codes.
+        fn stops() -> Vec<String> {
+            vec!["45242629".to_string(), "6090235412".to_string()]
+        }
+
+        #[rstest]
+        pub fn parses_situation_elements(sx_response: &str) {
+            let situations = parse_situations(sx_response).unwrap();
+            assert_eq!(situations.len(), 5);
+            assert_eq!(
+                situations[0],
+                Situation {
+                    summary: "Stop closed for roadworks on Duke St".to_string(),
+                    open: true,
+                    start_time: Some(DateTime::parse_from_rfc3339("2024-03-09T06:00:00Z").unwrap()),
+                    end_time: Some(DateTime::parse_from_rfc3339("2024-03-09T20:00:00Z").unwrap()),
+                    lines: vec![],
+                    stops: vec!["6090235412".to_string()],
+                }
+            );
+            assert_eq!(situations[1].lines, vec!["FGLA:60A", "60A"]);
+            assert!(!situations[2].open);
+        }
+
+        #[rstest]
+        pub fn keeps_active_situations_for_our_stop_and_lines(sx_response: &str) {
+            let lines = vec!["60A".to_string(), "61".to_string(), "17".to_string()];
+            let summaries: Vec<_> = parse_situations(sx_response)
+                .unwrap()
+                .into_iter()
+                .filter(|situation| situation.is_active(&clock()))
+                .filter(|situation| situation.affects(&stops(), &lines))
+                .map(|situation| situation.summary)
+                .collect();
+            // The 61 one is closed, the 17 one is over and nobody here takes the X99.
+            assert_eq!(
+                summaries,
+                vec![
+                    "Stop closed for roadworks on Duke St",
+                    "60A diverted via Cumbernauld Rd"
+                ]
+            );
+        }
+
+        #[rstest]
+        pub fn matches_stops_by_atco_code(sx_response: &str) {
+            let roadworks = &parse_situations(sx_response).unwrap()[0];
+            assert!(!roadworks.affects(&["45242629".to_string()], &[]));
+            assert!(roadworks.affects(&stops(), &[]));
+        }
+
+        #[rstest]
+        pub fn joins_summaries_for_the_ticker() {
+            let situation = |summary: &str| Situation {
+                summary: summary.to_string(),
+                ..Situation::default()
+            };
+            assert_eq!(ticker_text(&[]), None);
+            assert_eq!(
+                ticker_text(&[situation("Stop closed"), situation("60A diverted")]).as_deref(),
+                Some("Stop closed / 60A diverted")
+            );
+        }
+    }
+}
@@ -0,0 +1,176 @@
+pub mod tfl {
+    use crate::clock::clock::Clock;
+    use crate::next_buses::{env_var, BusStop, ExpectedBusArrival, NextBusesConfig};
+    use crate::transit_source::transit_source::TransitSource;
+    use anyhow::{anyhow, Result};
+    use async_trait::async_trait;
+    use chrono::{DateTime, FixedOffset};
+    use reqwest::header::USER_AGENT;
+    use serde::Deserialize;
+
+    const TFL_ENDPOINT: &str = "https://api.tfl.gov.uk";
+
+    /// The parts of a TfL `Prediction` that make it onto the board.
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Prediction {
+        line_name: String,
+        naptan_id: Option<String>,
+        destination_name: Option<String>,
+        mode_name: Option<String>,
+        timestamp: Option<DateTime<FixedOffset>>,
+        expected_arrival: DateTime<FixedOffset>,
+    }
+
+    impl From<Prediction> for ExpectedBusArrival {
+        fn from(prediction: Prediction) -> Self {
+            ExpectedBusArrival {
+                line: prediction.line_name,
+                direction: prediction.destination_name,
+                stop: prediction.naptan_id,
+                // TfL stops are already known by their ATCO codes.
+                atco_code: None,
+                walking_minutes: 0,
+                expected_time: prediction.expected_arrival,
+                aimed_time: None,
+                // TfL only publishes predictions for buses it is tracking.
+                real_time: true,
+                departure_status: None,
+                // TfL doesn't say who runs each bus.
+                operator: None,
+                vehicle_mode: prediction
+                    .mode_name
+                    .and_then(|mode_name| mode_name.parse().ok()),
+                recorded_at: prediction.timestamp,
+                stale: false,
+            }
+        }
+    }
+
+    /// Reads the response from `StopPoint/{id}/Arrivals`, soonest first.
+    pub fn parse_arrivals(json: &str) -> Result<Vec<ExpectedBusArrival>> {
+        let predictions: Vec<Prediction> = serde_json::from_str(json)?;
+        let mut arrivals: Vec<ExpectedBusArrival> = predictions
+            .into_iter()
+            .map(ExpectedBusArrival::from)
+            .collect();
+        // TfL doesn't sort its predictions.
+        arrivals.sort_by_key(|arrival| arrival.expected_time);
+        Ok(arrivals)
+    }
+
+    /// The TfL Unified API's StopPoint arrivals, for displays in London.
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct TflSource {
+        pub endpoint: String,
+        pub app_key: Option<String>,
+    }
+
+    impl TflSource {
+        pub fn from_env() -> TflSource {
+            TflSource {
+                endpoint: env_var("TFL_ENDPOINT").unwrap_or_else(|| TFL_ENDPOINT.to_string()),
+                app_key: env_var("TFL_APP_KEY"),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl TransitSource for TflSource {
+        fn name(&self) -> &str {
+            "tfl"
+        }
+
+        async fn stop_visits(
+            &self,
+            _config: &NextBusesConfig,
+            stop: &BusStop,
+            _clock: &dyn Clock,
+        ) -> Result<Vec<ExpectedBusArrival>> {
+            let mut url = reqwest::Url::parse(&self.endpoint)?;
+            url.path_segments_mut()
+                .map_err(|_| anyhow!("Invalid TfL endpoint {}", self.endpoint))?
+                .extend(["StopPoint", &stop.naptan_code, "Arrivals"]);
+            if let Some(app_key) = &self.app_key {
+                url.query_pairs_mut().append_pair("app_key", app_key);
+            }
+            let api_response = reqwest::Client::new()
+                .get(url)
+                .header(USER_AGENT, "tidbyt")
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            parse_arrivals(&api_response)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::clock::clock::FixedClock;
+        use crate::next_buses::{select_arrivals, ArrivalFilter, VehicleMode};
+        use rstest::*;
+
+        #[fixture]
+        fn tfl_response() -> &'static str {
+            include_str!("../fixtures/tfl_arrivals.json")
+        }
+
+        fn clock() -> FixedClock {
+            FixedClock(DateTime::parse_from_rfc3339("2024-03-09T15:21:17Z").unwrap())
+        }
+
+        #[rstest]
+        pub fn parses_stop_point_arrivals(tfl_response: &str) {
+            let arrivals = parse_arrivals(tfl_response).unwrap();
+            let summary: Vec<_> = arrivals
+                .iter()
+                .map(|arrival| (arrival.line.as_str(), arrival.direction.as_deref()))
+                .collect();
+            assert_eq!(
+                summary,
+                vec![
+                    ("390", Some("Archway")),
+                    ("73", Some("Stoke Newington")),
+                    ("73", Some("Stoke Newington")),
+                    ("98", Some("Holborn")),
+                ]
+            );
+            assert_eq!(
+                arrivals[0].expected_time,
+                DateTime::parse_from_rfc3339("2024-03-09T15:22:51Z").unwrap()
+            );
+            assert_eq!(arrivals[0].stop.as_deref(), Some("490008660N"));
+            assert!(arrivals.iter().all(|arrival| arrival.real_time));
+            assert_eq!(arrivals[0].vehicle_mode, Some(VehicleMode::Bus));
+            assert!(arrivals[0].recorded_at.is_some());
+        }
+
+        #[rstest]
+        pub fn selects_catchable_arrivals(tfl_response: &str) {
+            let config = NextBusesConfig {
+                filter: ArrivalFilter {
+                    exclude_lines: vec!["98".to_string()],
+                    ..ArrivalFilter::default()
+                },
+                ..NextBusesConfig::new(vec![BusStop {
+                    naptan_code: "490008660N".to_string(),
+                    minutes_away: 4,
+                }])
+            };
+            let arrivals = select_arrivals(
+                parse_arrivals(tfl_response).unwrap(),
+                &config,
+                &config.stops[0],
+                &clock(),
+            );
+            let summary: Vec<_> = arrivals
+                .iter()
+                .map(|arrival| (arrival.line.as_str(), arrival.walking_minutes))
+                .collect();
+            assert_eq!(summary, vec![("73", 4), ("73", 4)]);
+        }
+    }
+}
@@ -0,0 +1,193 @@
+pub mod transit_source {
+    use crate::arrivals_cache::arrivals_cache::CachedSource;
+    use crate::clock::clock::Clock;
+    use crate::gtfs_realtime::gtfs_realtime::GtfsRealtimeSource;
+    use crate::gtfs_static::gtfs_static::GtfsStaticSource;
+    use crate::next_buses::{
+        env_var, select_arrivals, BusStop, ExpectedBusArrival, NextBusesConfig, NextBusesSource,
+    };
+    use crate::tfl::tfl::TflSource;
+    use anyhow::{anyhow, Result};
+    use async_trait::async_trait;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    // Leaves time to fall back before the scheduled workflow is killed.
+    const LIVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Somewhere to look up live departures from a stop.
+    #[async_trait]
+    pub trait TransitSource: Send + Sync {
+        /// Tells responses from different sources apart, e.g. in the cache.
+        fn name(&self) -> &str;
+
+        /// Every departure from `stop` that the source gave, before any
+        /// filtering.
+        async fn stop_visits(
+            &self,
+            config: &NextBusesConfig,
+            stop: &BusStop,
+            clock: &dyn Clock,
+        ) -> Result<Vec<ExpectedBusArrival>>;
+
+        /// The departures from `stop` worth showing, already passed
+        /// through the config's filter and tagged with the walking time.
+        async fn stop_arrivals(
+            &self,
+            config: &NextBusesConfig,
+            stop: &BusStop,
+            clock: &dyn Clock,
+        ) -> Result<Vec<ExpectedBusArrival>> {
+            let visits = self.stop_visits(config, stop, clock).await?;
+            Ok(select_arrivals(visits, config, stop, clock))
+        }
+    }
+
+    /// Whether `err` is the network or the API letting us down, rather
+    /// than something wrong with the request. Only then are older or
+    /// timetabled departures worth showing instead, since otherwise a
+    /// bad login or stop code would never be noticed.
+    pub fn is_transient(err: &anyhow::Error) -> bool {
+        if err.is::<tokio::time::error::Elapsed>() {
+            return true;
+        }
+        match err.downcast_ref::<reqwest::Error>() {
+            Some(err) => !err.status().is_some_and(|status| status.is_client_error()),
+            None => false,
+        }
+    }
+
+    /// The error a request that took too long fails with.
+    #[cfg(test)]
+    pub(crate) async fn timed_out() -> anyhow::Error {
+        tokio::time::timeout(Duration::ZERO, std::future::pending::<()>())
+            .await
+            .unwrap_err()
+            .into()
+    }
+
+    /// Uses `fallback` whenever `live` can't be reached or takes too long.
+    pub struct WithFallback {
+        pub live: Arc<dyn TransitSource>,
+        pub fallback: Arc<dyn TransitSource>,
+    }
+
+    #[async_trait]
+    impl TransitSource for WithFallback {
+        fn name(&self) -> &str {
+            self.live.name()
+        }
+
+        async fn stop_visits(
+            &self,
+            config: &NextBusesConfig,
+            stop: &BusStop,
+            clock: &dyn Clock,
+        ) -> Result<Vec<ExpectedBusArrival>> {
+            let live =
+                tokio::time::timeout(LIVE_TIMEOUT, self.live.stop_visits(config, stop, clock))
+                    .await
+                    .unwrap_or_else(|elapsed| Err(elapsed.into()));
+            match live {
+                Ok(arrivals) => Ok(arrivals),
+                Err(err) if is_transient(&err) => {
+                    println!(
+                        "Live departures for {} failed, using the timetable: {}",
+                        stop.naptan_code, err
+                    );
+                    self.fallback.stop_visits(config, stop, clock).await
+                }
+                Err(err) => Err(err),
+            }
+        }
+    }
+
+    /// Picks the source named by `TRANSIT_SOURCE`, defaulting to NextBuses.
+    /// Its responses are cached on disk, and backed by the GTFS timetable
+    /// at `GTFS_STATIC_ZIP` if there is one.
+    pub fn from_env() -> Result<Arc<dyn TransitSource>> {
+        let source = env_var("TRANSIT_SOURCE").unwrap_or_else(|| "nextbuses".to_string());
+        let live: Arc<dyn TransitSource> = match source.as_str() {
+            "nextbuses" => Arc::new(NextBusesSource),
+            "tfl" => Arc::new(TflSource::from_env()),
+            "gtfs-rt" => Arc::new(GtfsRealtimeSource::from_env()?),
+            _ => return Err(anyhow!("Unknown transit source {}", source)),
+        };
+        let live = CachedSource::wrap_from_env(live)?;
+        Ok(match env_var("GTFS_STATIC_ZIP") {
+            Some(path) => Arc::new(WithFallback {
+                live,
+                fallback: Arc::new(GtfsStaticSource::new(path.into())),
+            }),
+            None => live,
+        })
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::clock::clock::FixedClock;
+        use crate::next_buses::{SiriError, SiriErrorKind};
+        use chrono::DateTime;
+        use rstest::*;
+
+        // Fails with a timeout, or with an error from the API when it has one.
+        struct Failing(Option<SiriErrorKind>);
+
+        #[async_trait]
+        impl TransitSource for Failing {
+            fn name(&self) -> &str {
+                "failing"
+            }
+
+            async fn stop_visits(
+                &self,
+                _config: &NextBusesConfig,
+                _stop: &BusStop,
+                _clock: &dyn Clock,
+            ) -> Result<Vec<ExpectedBusArrival>> {
+                match self.0 {
+                    Some(kind) => Err(SiriError::new(kind).into()),
+                    None => Err(timed_out().await),
+                }
+            }
+        }
+
+        async fn with_fallback(live: Failing) -> Result<Vec<ExpectedBusArrival>> {
+            let source = WithFallback {
+                live: Arc::new(live),
+                fallback: Arc::new(GtfsStaticSource::new(
+                    concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/gtfs_static.zip").into(),
+                )),
+            };
+            let config = NextBusesConfig::new(vec![BusStop {
+                naptan_code: "45242629".to_string(),
+                minutes_away: 0,
+            }]);
+            let clock = FixedClock(DateTime::parse_from_rfc3339("2024-03-09T15:21:17Z").unwrap());
+            source
+                .stop_arrivals(&config, &config.stops[0], &clock)
+                .await
+        }
+
+        #[rstest]
+        #[tokio::test]
+        pub async fn falls_back_to_the_timetable_when_live_fails() {
+            let arrivals = with_fallback(Failing(None)).await.unwrap();
+            let lines: Vec<_> = arrivals
+                .iter()
+                .map(|arrival| (arrival.line.as_str(), arrival.real_time))
+                .collect();
+            assert_eq!(lines, vec![("60A", false), ("61", false)]);
+        }
+
+        #[rstest]
+        #[tokio::test]
+        pub async fn passes_on_errors_from_the_api() {
+            let err = with_fallback(Failing(Some(SiriErrorKind::UnknownStop)))
+                .await
+                .unwrap_err();
+            assert!(err.is::<SiriError>());
+        }
+    }
+}